@@ -0,0 +1,171 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::{error, info};
+use ring::constant_time::verify_slices_are_equal;
+use serde::Deserialize;
+
+use crate::{
+    api::source_ip,
+    audit::{AuditEvent, AuditEventKind},
+    errors::AdminError,
+    settings::Settings,
+    state::State,
+};
+
+/// Register the routes of the administrative API.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .route("/grants", web::get().to(grants))
+            .route("/users/{sub}/tokens", web::delete().to(revoke))
+            .route("/clients", web::get().to(clients))
+            .route("/keys/rotate", web::post().to(rotate_key))
+            .route("/reload", web::post().to(reload)),
+    );
+}
+
+/// Check the bearer token of a request to the administrative API.
+fn check_authorization(req: &HttpRequest, state: &State) -> Result<(), AdminError> {
+    let settings = state.settings();
+    let expected = match &settings.admin.token {
+        Some(token) if settings.admin.enabled && !token.is_empty() => token,
+        _ => return Err(AdminError::Disabled),
+    };
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(AdminError::Unauthorized)?;
+    verify_slices_are_equal(token.as_bytes(), expected.as_bytes())
+        .map_err(|_| AdminError::Unauthorized)
+}
+
+/// Read the configuration file the server has been started with again.
+fn read_config_file(state: &State) -> Result<Settings, AdminError> {
+    let config_file = state
+        .settings()
+        .config_file
+        .clone()
+        .ok_or(AdminError::NoConfigFile)?;
+    Settings::with_file(config_file.as_str())
+        .inspect_err(|e| error!("Could not read configuration file {}: {:?}", config_file, e))
+        .map_err(AdminError::from)
+}
+
+#[derive(Deserialize)]
+pub struct GrantFilter {
+    sub: Option<String>,
+    client_id: Option<String>,
+}
+
+/// List the active refresh token grants, optionally only of a user or client.
+pub async fn grants(
+    (req, query, state): (HttpRequest, web::Query<GrantFilter>, web::Data<State>),
+) -> Result<HttpResponse, AdminError> {
+    check_authorization(&req, &state)?;
+    let grants: Vec<_> = state
+        .issuer()
+        .active_grants()
+        .into_iter()
+        .filter(|grant| query.sub.as_ref().map_or(true, |sub| &grant.sub == sub))
+        .filter(|grant| {
+            query
+                .client_id
+                .as_ref()
+                .map_or(true, |client_id| &grant.client_id == client_id)
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "grants": grants })))
+}
+
+#[derive(Deserialize)]
+pub struct ClientFilter {
+    client_id: Option<String>,
+}
+
+/// Revoke all refresh tokens of a user, optionally only for a single client.
+pub async fn revoke(
+    (req, sub, query, state): (
+        HttpRequest,
+        web::Path<String>,
+        web::Query<ClientFilter>,
+        web::Data<State>,
+    ),
+) -> Result<HttpResponse, AdminError> {
+    check_authorization(&req, &state)?;
+    let revoked = state.issuer().revoke(&sub, query.client_id.as_deref());
+    info!(
+        "Administrator revoked {} refresh tokens of {}",
+        revoked, sub
+    );
+    state.audit.record(&AuditEvent {
+        client_id: query.client_id.clone(),
+        sub: Some(sub.into_inner()),
+        source_ip: source_ip(&req, &state),
+        ..AuditEvent::new(AuditEventKind::Revoke, "revoked")
+    });
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "revoked": revoked })))
+}
+
+/// List the registered clients without their secrets.
+pub async fn clients(
+    (req, state): (HttpRequest, web::Data<State>),
+) -> Result<HttpResponse, AdminError> {
+    check_authorization(&req, &state)?;
+    let clients: Vec<_> = state
+        .registrar()
+        .clients()
+        .into_iter()
+        .map(|client| {
+            let mut redirect_uris = vec![client.redirect_uri.clone()];
+            redirect_uris.extend(client.additional_redirect_uris.iter().cloned());
+            serde_json::json!({
+                "client_id": client.id,
+                "redirect_uris": redirect_uris,
+                "allowed_scopes": client.allowed_scopes,
+                "default_scopes": client.default_scopes,
+                "confidential": client.secret.is_some(),
+                "token_endpoint_auth_method": client.auth_method().as_str(),
+                "require_consent": client.require_consent,
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "clients": clients })))
+}
+
+/// Sign tokens with the key that is currently in the configuration file.
+///
+/// Previous keys are still accepted when verifying tokens, so tokens issued
+/// before remain valid until they expire.
+pub async fn rotate_key(
+    (req, state): (HttpRequest, web::Data<State>),
+) -> Result<HttpResponse, AdminError> {
+    check_authorization(&req, &state)?;
+    let settings = read_config_file(&state)?;
+    let key = settings.client.token_verification;
+    let rotated = key != state.settings().client.token_verification;
+    let algorithm = format!("{:?}", key.as_algorithm());
+    state
+        .rotate_key(key)
+        .inspect_err(|e| error!("Could not rotate signing key: {:?}", e))?;
+    if rotated {
+        info!("Administrator rotated the signing key");
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "rotated": rotated,
+        "algorithm": algorithm,
+    })))
+}
+
+/// Read the configuration file again and apply the settings that can be changed at runtime.
+pub async fn reload(
+    (req, state): (HttpRequest, web::Data<State>),
+) -> Result<HttpResponse, AdminError> {
+    check_authorization(&req, &state)?;
+    let settings = read_config_file(&state)?;
+    state
+        .reload(settings)
+        .inspect_err(|e| error!("Could not reload configuration: {:?}", e))?;
+    info!("Administrator reloaded the configuration");
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "reloaded" })))
+}
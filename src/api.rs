@@ -1,43 +1,105 @@
-use std::collections::HashMap;
+use std::{borrow::Cow, cell::Cell, future::Future};
 
-use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, HttpResponseBuilder};
-use log::{debug, error};
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse, HttpResponseBuilder, Responder,
+};
+use chrono::{Duration, Utc};
+use log::{debug, error, warn};
 use oxide_auth::{
+    code_grant::accesstoken::TokenResponse,
     endpoint::{
         AccessTokenExtension, AccessTokenFlow, AuthorizationExtension, AuthorizationFlow,
-        Extension, OwnerConsent, RefreshFlow, Solicitation,
+        Extension, NormalizedParameter, OAuthError, OwnerConsent, QueryParameter, RefreshFlow,
+        Solicitation, WebResponse,
     },
     frontends::simple::{endpoint::FnSolicitor, extensions::Extended},
-    primitives::grant::{Extensions, Value},
+    primitives::{
+        grant::{Extensions, Grant, Value},
+        prelude::{ClientUrl, Issuer, PreGrant, Registrar, Scope},
+        registrar::ExactUrl,
+    },
 };
 use oxide_auth_actix::{OAuthRequest, OAuthResponse, WebError};
+use serde::Deserialize;
+
+use crate::{
+    attributes::AttributeValue,
+    audit::{AuditEvent, AuditEventKind},
+    client_auth::{ClientAuthRequest, ClientCredentials},
+    consent::CsrfForm,
+    device::{format_user_code, DeviceStatus},
+    errors::RuntimeError,
+    identity::{identity_source, Identity},
+    jwt::{generate_jti, NONCE_EXTENSION},
+    metrics::Denial,
+    pages::Page,
+    settings::{ClientAuthMethod, ResponseMode},
+    state::State,
+};
 
-use crate::{settings::Settings, state::State};
+pub(crate) const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+pub(crate) const TOKEN_EXCHANGE_GRANT_TYPE: &str =
+    "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
 
-struct HeaderExtension {
-    headers: HashMap<String, String>,
+/// Claims of a request object that are not parameters of the authorization request.
+const REQUEST_OBJECT_CLAIMS: &[&str] = &[
+    "iss",
+    "aud",
+    "exp",
+    "nbf",
+    "iat",
+    "jti",
+    "request",
+    "request_uri",
+];
+
+/// An AuthorizationExtension that adds the attributes of the user to the grant.
+struct IdentityExtension {
+    extensions: Extensions,
+    /// The `nonce` of the authorization request, which is kept as private
+    /// extension, so it is not mixed up with the attributes
+    nonce: Option<String>,
 }
 
-impl Extension for HeaderExtension {
+impl Extension for IdentityExtension {
     fn authorization(&mut self) -> Option<&mut dyn AuthorizationExtension> {
         Some(self)
     }
 }
 
-impl AuthorizationExtension for HeaderExtension {
+impl AuthorizationExtension for IdentityExtension {
     fn extend(
         &mut self,
         _request: &dyn oxide_auth::code_grant::authorization::Request,
     ) -> std::result::Result<Extensions, ()> {
-        let mut extensions = Extensions::new();
-        // Set all extensions by using the header values
-        for (n, v) in &self.headers {
-            extensions.set_raw(n.to_string(), Value::Public(Some(v.to_string())));
+        let mut extensions = self.extensions.clone();
+        if let Some(nonce) = &self.nonce {
+            extensions.set_raw(
+                NONCE_EXTENSION.to_string(),
+                Value::private(Some(nonce.clone())),
+            );
         }
         Ok(extensions)
     }
 }
 
+/// Determine the identity of the user with the identity source of the client.
+fn identify(http_req: &HttpRequest, client_id: Option<&str>, state: &State) -> Identity {
+    let source = client_id
+        .and_then(|client_id| {
+            state
+                .registrar()
+                .client(client_id)
+                .map(|client| client.identity_source.clone())
+        })
+        .unwrap_or_default();
+    identity_source(&source).identify(http_req, &state.settings().mapping)
+}
+
 /// An AccessTokenExtension that just copies all extensions from the authorize request.
 struct CopyExtension {}
 
@@ -57,105 +119,1170 @@ impl AccessTokenExtension for CopyExtension {
     }
 }
 
+/// Verify the signed request object of an authorization request (RFC 9101)
+/// and return the parameters of the request.
+fn apply_request_object(
+    client_id: &str,
+    request: &str,
+    state: &State,
+) -> Option<NormalizedParameter> {
+    let claims = state.registrar().verify_request_object(
+        client_id,
+        request,
+        &state.settings().authorization.issuer,
+    )?;
+    if claims
+        .get("client_id")
+        .is_some_and(|claim| claim.as_str() != Some(client_id))
+    {
+        debug!(
+            "The request object of client {} is for another client",
+            client_id
+        );
+        return None;
+    }
+
+    // Only the parameters of the request object are used, because the others
+    // could have been changed in the browser (RFC 9101, section 6.3)
+    let mut parameters = NormalizedParameter::new();
+    for (name, value) in claims.iter() {
+        if REQUEST_OBJECT_CLAIMS.contains(&name.as_str()) {
+            continue;
+        }
+        let value = match value {
+            serde_json::Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        parameters.insert_or_poison(Cow::Owned(name.clone()), Cow::Owned(value));
+    }
+    if !claims.contains_key("client_id") {
+        parameters.insert_or_poison(
+            Cow::Borrowed("client_id"),
+            Cow::Owned(client_id.to_string()),
+        );
+    }
+    Some(parameters)
+}
+
 pub async fn authorize(
-    (auth_request, http_req, state): (OAuthRequest, HttpRequest, web::Data<State>),
-) -> Result<OAuthResponse, WebError> {
-    let headers = http_req.headers().clone();
-    let settings = state.settings.clone();
+    (mut auth_request, http_req, state): (OAuthRequest, HttpRequest, web::Data<State>),
+) -> Result<HttpResponse, WebError> {
+    let query_value = |auth_request: &OAuthRequest, name: &str| {
+        auth_request
+            .query()
+            .and_then(|query| query.unique_value(name))
+            .map(|value| value.to_string())
+    };
+    let client_id = query_value(&auth_request, "client_id");
+    let error_page = |error_code| {
+        state.pages.render(
+            Page::InvalidClient,
+            error_code,
+            client_id.as_deref(),
+            &http_req,
+        )
+    };
+    // The parameters of a pushed request replace the ones of the request (RFC 9126)
+    let request_uri = query_value(&auth_request, "request_uri");
+    // A pushed request object has already been verified at the /par endpoint
+    let mut signed = false;
+    if let Some(request_uri) = &request_uri {
+        let pushed = client_id
+            .as_deref()
+            .and_then(|client_id| state.pushed_requests().get(request_uri, client_id));
+        match (pushed, auth_request.query_mut()) {
+            (Some((parameters, pushed_signed)), Some(query)) => {
+                *query = parameters;
+                signed = pushed_signed;
+            }
+            _ => {
+                debug!(
+                    "Unknown or expired request_uri of client {}",
+                    client_id.as_deref().unwrap_or_default()
+                );
+                return Ok(error_page("invalid_request_uri"));
+            }
+        }
+    }
+    if let Some(request) = query_value(&auth_request, "request") {
+        let parameters = client_id
+            .as_deref()
+            .and_then(|client_id| apply_request_object(client_id, &request, &state));
+        match (parameters, auth_request.query_mut()) {
+            (Some(parameters), Some(query)) => *query = parameters,
+            _ => return Ok(error_page("invalid_request_object")),
+        }
+    } else if !signed
+        && client_id.as_deref().is_some_and(|client_id| {
+            state
+                .registrar()
+                .client(client_id)
+                .is_some_and(|client| client.require_request_object)
+        })
+    {
+        debug!(
+            "Client {} has to send a request object",
+            client_id.as_deref().unwrap_or_default()
+        );
+        return Ok(error_page("invalid_request_object"));
+    }
+    let response_modes = client_id
+        .as_deref()
+        .and_then(|client_id| {
+            state
+                .registrar()
+                .client(client_id)
+                .map(|client| client.response_modes.clone())
+        })
+        .unwrap_or_default();
+    let response_mode = match query_value(&auth_request, "response_mode") {
+        Some(mode) => {
+            ResponseMode::from_parameter(&mode).filter(|mode| response_modes.contains(mode))
+        }
+        None => Some(
+            response_modes
+                .first()
+                .copied()
+                .unwrap_or(ResponseMode::Query),
+        ),
+    };
+    let response_mode = match response_mode {
+        Some(response_mode) => response_mode,
+        None => {
+            debug!(
+                "Response mode not allowed for client {}",
+                client_id.as_deref().unwrap_or_default()
+            );
+            return Ok(error_page("invalid_request"));
+        }
+    };
+    let nonce = query_value(&auth_request, "nonce");
+    let (require_state, require_nonce) = client_id
+        .as_deref()
+        .and_then(|client_id| {
+            state
+                .registrar()
+                .client(client_id)
+                .map(|client| (client.require_state, client.require_nonce))
+        })
+        .unwrap_or_default();
+    if (require_state && query_value(&auth_request, "state").is_none())
+        || (require_nonce && nonce.is_none())
+    {
+        debug!(
+            "Missing state or nonce in authorization request of client {}",
+            client_id.as_deref().unwrap_or_default()
+        );
+        return Ok(error_page("invalid_request"));
+    }
+    let identity = identify(&http_req, client_id.as_deref(), &state);
+    let require_consent = client_id
+        .as_deref()
+        .and_then(|client_id| {
+            state
+                .registrar()
+                .client(client_id)
+                .map(|client| client.require_consent)
+        })
+        .unwrap_or(false);
+    let source_ip = source_ip(&http_req, &state);
+    let consent_pending = Cell::new(false);
     let endpoint = state.endpoint().with_solicitor(FnSolicitor(
-        move |_request: &mut OAuthRequest, _pre_grant: Solicitation| match &settings
-            .mapping
-            .sub_header
-        {
-            Some(sub_header) => {
-                if let Some(remote_user) = headers.get(sub_header) {
-                    if let Ok(remote_user) = remote_user.to_str() {
-                        if !remote_user.is_empty() {
-                            return OwnerConsent::Authorized(remote_user.to_string());
-                        }
-                    }
+        |request: &mut OAuthRequest, solicitation: Solicitation| {
+            let pre_grant = solicitation.pre_grant();
+            let consent = match &identity.owner {
+                Some(owner) if require_consent => {
+                    ask_consent(request, pre_grant, owner, &identity, &state, &http_req)
                 }
-                OwnerConsent::Denied
-            }
-            None => OwnerConsent::Authorized(settings.mapping.default_sub.clone()),
+                Some(owner) => OwnerConsent::Authorized(owner.clone()),
+                None => {
+                    state.metrics.record_denial(Denial::MissingIdentity);
+                    OwnerConsent::Denied
+                }
+            };
+            let outcome = match consent {
+                OwnerConsent::Authorized(_) => "approved",
+                OwnerConsent::Denied => "denied",
+                OwnerConsent::InProgress(_) => {
+                    consent_pending.set(true);
+                    "consent_required"
+                }
+                OwnerConsent::Error(_) => "error",
+            };
+            state.audit.record(&AuditEvent {
+                client_id: Some(pre_grant.client_id.clone()),
+                sub: identity.owner.clone(),
+                source_ip: source_ip.clone(),
+                attributes: state.audit.attributes(&identity.attributes),
+                ..AuditEvent::new(AuditEventKind::Authorize, outcome)
+            });
+            consent
         },
     ));
-    // Add all configured headers to map
-    let headers: HashMap<_, _> = state
-        .settings
-        .mapping
-        .include_headers
+    // Add all configured attributes to the grant
+    let extension = IdentityExtension {
+        extensions: identity.extensions(),
+        nonce,
+    };
+    let extended = Extended::extend_with(endpoint, extension);
+
+    let result = AuthorizationFlow::prepare(extended)
+        .and_then(|mut flow| flow.execute(auth_request))
+        .map_err(WebError::from);
+    // A pushed request can only be used once, except for submitting the consent form
+    if let Some(request_uri) = &request_uri {
+        if !consent_pending.get() {
+            state.pushed_requests().remove(request_uri);
+        }
+    }
+    match result {
+        Ok(response) => Ok(authorization_response(
+            response.respond_to(&http_req),
+            response_mode,
+            &http_req,
+            &state,
+        )),
+        // Errors that can not be reported to the client by redirecting the user
+        Err(e) => {
+            let (page, error_code) = match e {
+                WebError::Endpoint(OAuthError::DenySilently)
+                | WebError::Endpoint(OAuthError::BadRequest)
+                | WebError::Query => (Page::InvalidClient, "invalid_request"),
+                _ => {
+                    error!("Could not authorize: {}", e);
+                    (Page::Error, "server_error")
+                }
+            };
+            Ok(state
+                .pages
+                .render(page, error_code, client_id.as_deref(), &http_req))
+        }
+    }
+}
+
+/// Parameters of the authorization response, which are returned as requested
+/// by the response mode instead of in the query of the redirect URI.
+const RESPONSE_PARAMETERS: &[&str] = &["code", "state", "error", "error_description", "error_uri"];
+
+/// Return the redirect of the authorization flow in the response mode of the
+/// request, with the issuer as `iss` parameter to prevent mix-up attacks (RFC 9207).
+///
+/// Other responses, like the consent page, are returned unchanged.
+fn authorization_response(
+    response: HttpResponse,
+    response_mode: ResponseMode,
+    http_req: &HttpRequest,
+    state: &State,
+) -> HttpResponse {
+    let location = response
+        .headers()
+        .get(header::LOCATION)
+        .filter(|_| response.status() == StatusCode::FOUND)
+        .and_then(|location| location.to_str().ok())
+        .and_then(|location| url::Url::parse(location).ok());
+    let mut redirect_uri = match location {
+        Some(location) => location,
+        None => return response,
+    };
+    let (mut parameters, query): (Vec<_>, Vec<_>) = redirect_uri
+        .query_pairs()
+        .into_owned()
+        .partition(|(name, _)| RESPONSE_PARAMETERS.contains(&name.as_str()));
+    parameters.push((
+        "iss".to_string(),
+        state.settings().authorization.issuer.clone(),
+    ));
+
+    redirect_uri.set_query(None);
+    if response_mode == ResponseMode::Query {
+        redirect_uri
+            .query_pairs_mut()
+            .extend_pairs(query.iter().chain(&parameters));
+    } else if !query.is_empty() {
+        redirect_uri.query_pairs_mut().extend_pairs(&query);
+    }
+    match response_mode {
+        ResponseMode::Query => {}
+        ResponseMode::Fragment => {
+            let fragment = url::form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(&parameters)
+                .finish();
+            redirect_uri.set_fragment(Some(&fragment));
+        }
+        ResponseMode::FormPost => {
+            let variables = serde_json::json!({
+                "redirect_uri": redirect_uri.as_str(),
+                "parameters": parameters
+                    .into_iter()
+                    .map(|(name, value)| (name, value.into()))
+                    .collect::<serde_json::Map<_, _>>(),
+            });
+            return match state.pages.render_html(
+                "form_post",
+                include_str!("form-post.html"),
+                http_req,
+                variables,
+            ) {
+                Ok(page) => HttpResponse::Ok()
+                    .content_type("text/html; charset=utf-8")
+                    .insert_header((header::CACHE_CONTROL, "no-store"))
+                    .body(page),
+                Err(e) => {
+                    error!("Could not render form_post page: {}", e);
+                    state
+                        .pages
+                        .render(Page::Error, "server_error", None, http_req)
+                }
+            };
+        }
+    }
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, redirect_uri.as_str()))
+        .finish()
+}
+
+/// Ask the user to consent to sharing their attributes with the client, unless
+/// they already did, or handle the submitted consent form.
+fn ask_consent(
+    request: &OAuthRequest,
+    pre_grant: &PreGrant,
+    owner: &str,
+    identity: &Identity,
+    state: &State,
+    http_req: &HttpRequest,
+) -> OwnerConsent<OAuthResponse> {
+    let form_value = |name: &str| {
+        request
+            .body()
+            .and_then(|body| body.unique_value(name))
+            .map(|value| value.to_string())
+    };
+    let client_id = &pre_grant.client_id;
+
+    if let Some(decision) = form_value("consent") {
+        let mut consents = state.consents();
+        let csrf_token = form_value("csrf_token").unwrap_or_default();
+        if !consents.verify_csrf_token(&csrf_token, CsrfForm::Consent, owner, client_id) {
+            warn!("Invalid CSRF token in consent form of client {}", client_id);
+            return OwnerConsent::Denied;
+        }
+        if decision != "allow" {
+            return OwnerConsent::Denied;
+        }
+        if form_value("remember").as_deref() == Some("true") {
+            consents.remember(owner, client_id, &pre_grant.scope);
+        }
+        return OwnerConsent::Authorized(owner.to_string());
+    }
+
+    if state
+        .consents()
+        .has_consent(owner, client_id, &pre_grant.scope)
+    {
+        return OwnerConsent::Authorized(owner.to_string());
+    }
+
+    // Show the attributes that will be available for the token
+    let attributes: serde_json::Map<String, serde_json::Value> = identity
+        .attributes
         .iter()
-        .filter_map(|name| {
-            if let Some(value) = http_req.headers().get(name) {
-                Some((
-                    name.to_string(),
-                    value.to_str().unwrap_or_default().to_string(),
-                ))
-            } else {
-                None
-            }
+        .filter(|(name, _)| {
+            state
+                .settings()
+                .mapping
+                .is_header_visible(name, &pre_grant.scope)
+        })
+        .map(|(name, value)| {
+            let value = match value {
+                AttributeValue::Single(value) => value.clone(),
+                AttributeValue::List(values) => values.join(", "),
+            };
+            (name.to_string(), value.into())
         })
         .collect();
-    let extension = HeaderExtension { headers };
-    let extended = Extended::extend_with(endpoint, extension);
+    let variables = serde_json::json!({
+        "client_id": client_id,
+        "scope": pre_grant.scope.to_string(),
+        "attributes": attributes,
+        "csrf_token": state.consents().csrf_token(CsrfForm::Consent, owner, client_id),
+    });
+    let page =
+        match state
+            .pages
+            .render_html("consent", include_str!("consent.html"), http_req, variables)
+        {
+            Ok(page) => page,
+            Err(e) => {
+                error!("Could not render consent page: {}", e);
+                return OwnerConsent::Error(WebError::InternalError(None));
+            }
+        };
+    match OAuthResponse::ok().content_type("text/html; charset=utf-8") {
+        Ok(response) => OwnerConsent::InProgress(response.body(&page)),
+        Err(e) => OwnerConsent::Error(e),
+    }
+}
+
+/// Create a JSON error response as defined in section 5.2 of RFC 6749.
+fn json_error(error: &str, unauthorized: bool) -> Result<OAuthResponse, WebError> {
+    let mut response = OAuthResponse::ok();
+    if unauthorized {
+        response.unauthorized("Basic")?;
+    } else {
+        response.client_error()?;
+    }
+    response.body_json(&serde_json::json!({ "error": error }).to_string())?;
+    Ok(response)
+}
+
+/// The IP address of the user for the audit log.
+///
+/// The `X-Forwarded-For` header is only used for requests from a trusted
+/// proxy. Its last address that is not a trusted proxy is taken, since the
+/// addresses before could have been sent by the user.
+pub(crate) fn source_ip(http_req: &HttpRequest, state: &State) -> Option<String> {
+    let trusted_proxies = &state.settings().audit.trusted_proxies;
+    let mut ip = http_req.peer_addr()?.ip();
+    if trusted_proxies.contains(&ip) {
+        let forwarded: Vec<&str> = http_req
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        for address in forwarded.into_iter().rev() {
+            match address.trim().parse() {
+                Ok(address) => ip = address,
+                Err(_) => break,
+            }
+            if !trusted_proxies.contains(&ip) {
+                break;
+            }
+        }
+    }
+    Some(ip.to_string())
+}
+
+/// Convert the response of the token endpoint and record failed requests in
+/// the audit log, issued tokens are recorded by the issuer.
+fn audit_token_response(
+    kind: AuditEventKind,
+    client_id: Option<String>,
+    response: Result<OAuthResponse, WebError>,
+    http_req: &HttpRequest,
+    state: &State,
+) -> Result<HttpResponse, WebError> {
+    let response = response.map(|response| response.respond_to(http_req));
+    if !response
+        .as_ref()
+        .is_ok_and(|response| response.status().is_success())
+    {
+        state.audit.record(&AuditEvent {
+            client_id,
+            source_ip: source_ip(http_req, state),
+            ..AuditEvent::new(kind, "failed")
+        });
+    }
+    response
+}
+
+/// Authenticate the client of a request to the token endpoint and return its ID.
+///
+/// The client has to use the authentication method it is registered with.
+pub(crate) fn authenticate_client(auth_request: &OAuthRequest, state: &State) -> Option<String> {
+    let credentials = ClientCredentials::from_request(auth_request)?;
+    let registrar = state.registrar();
+    if !registrar.allows_auth_method(&credentials.client_id, credentials.method) {
+        return None;
+    }
+    registrar
+        .check(
+            &credentials.client_id,
+            credentials.credential.as_ref().map(|c| c.as_bytes()),
+        )
+        .ok()?;
+    Some(credentials.client_id)
+}
 
-    AuthorizationFlow::prepare(extended)?
-        .execute(auth_request)
-        .map_err(WebError::from)
+/// Check that the client uses the authentication method it is registered with,
+/// before its credentials are checked by oxide-auth.
+fn allows_auth_method(credentials: Option<&ClientCredentials>, state: &State) -> bool {
+    credentials.map_or(true, |credentials| {
+        state
+            .registrar()
+            .allows_auth_method(&credentials.client_id, credentials.method)
+    })
+}
+
+/// Check that the client may use a grant type, unknown clients are rejected
+/// later when they are authenticated.
+fn allows_grant_type(client_id: Option<&str>, grant_type: &str, state: &State) -> bool {
+    client_id.map_or(true, |client_id| {
+        state
+            .registrar()
+            .client(client_id)
+            .map_or(true, |client| client.allows_grant_type(grant_type))
+    })
 }
 
 pub async fn token(
-    (auth_request, state): (OAuthRequest, web::Data<State>),
-) -> Result<OAuthResponse, WebError> {
-    let endpoint = state.endpoint();
+    (auth_request, http_req, state): (OAuthRequest, HttpRequest, web::Data<State>),
+) -> Result<HttpResponse, WebError> {
+    let credentials = ClientCredentials::from_request(&auth_request);
+    let client_id = credentials.as_ref().map(|c| c.client_id.clone());
+    let grant_type = auth_request
+        .body()
+        .and_then(|body| body.unique_value("grant_type"))
+        .map(|grant_type| grant_type.to_string());
+    let response = match grant_type.as_deref() {
+        Some(grant_type) if !allows_grant_type(client_id.as_deref(), grant_type, &state) => {
+            json_error("unauthorized_client", false)
+        }
+        Some(DEVICE_CODE_GRANT_TYPE) => device_token(&auth_request, &http_req, &state),
+        Some(TOKEN_EXCHANGE_GRANT_TYPE) => token_exchange(&auth_request, &http_req, &state),
+        _ if !allows_auth_method(credentials.as_ref(), &state) => {
+            json_error("invalid_client", true)
+        }
+        _ => {
+            let endpoint = state.token_endpoint(source_ip(&http_req, &state));
 
-    // Just copy the extensions from the authorize request in our token
-    let extension = CopyExtension {};
+            // Just copy the extensions from the authorize request in our token
+            let extension = CopyExtension {};
 
-    let extended = Extended::extend_with(endpoint, extension);
+            let extended = Extended::extend_with(endpoint, extension);
 
-    AccessTokenFlow::prepare(extended)?
-        .execute(auth_request)
-        .map_err(WebError::from)
+            AccessTokenFlow::prepare(extended)
+                .and_then(|mut flow| {
+                    flow.execute(ClientAuthRequest::new(&auth_request, credentials.as_ref()))
+                })
+                .map_err(ClientAuthRequest::web_error)
+        }
+    };
+    audit_token_response(
+        AuditEventKind::Token,
+        client_id,
+        response,
+        &http_req,
+        &state,
+    )
 }
 
 pub async fn refresh(
+    (auth_request, http_req, state): (OAuthRequest, HttpRequest, web::Data<State>),
+) -> Result<HttpResponse, WebError> {
+    let credentials = ClientCredentials::from_request(&auth_request);
+    let client_id = credentials.as_ref().map(|c| c.client_id.clone());
+    // A rotated refresh token revokes all tokens of its family and then fails like an unknown token
+    let reused = auth_request
+        .body()
+        .and_then(|body| body.unique_value("refresh_token"))
+        .and_then(|refresh_token| state.issuer().detect_reuse(&refresh_token));
+    if let Some(reused) = reused {
+        warn!(
+            "Revoked refresh tokens of {} for client {} because a rotated refresh token was used again",
+            reused.owner_id, reused.client_id
+        );
+        state.audit.record(&AuditEvent {
+            client_id: Some(reused.client_id),
+            sub: Some(reused.owner_id),
+            source_ip: source_ip(&http_req, &state),
+            ..AuditEvent::new(AuditEventKind::Refresh, "reused")
+        });
+    }
+    let response = if !allows_grant_type(client_id.as_deref(), "refresh_token", &state) {
+        json_error("unauthorized_client", false)
+    } else if allows_auth_method(credentials.as_ref(), &state) {
+        RefreshFlow::prepare(state.token_endpoint(source_ip(&http_req, &state)))
+            .and_then(|mut flow| {
+                flow.execute(ClientAuthRequest::new(&auth_request, credentials.as_ref()))
+            })
+            .map_err(ClientAuthRequest::web_error)
+    } else {
+        json_error("invalid_client", true)
+    };
+    audit_token_response(
+        AuditEventKind::Refresh,
+        client_id,
+        response,
+        &http_req,
+        &state,
+    )
+}
+
+/// Store the parameters of an authorization request of a confidential client
+/// (RFC 9126), which are then referenced by the returned `request_uri` at the
+/// authorization endpoint, so the URL stays short.
+pub async fn pushed_authorization_request(
+    (auth_request, http_req, state): (OAuthRequest, HttpRequest, web::Data<State>),
+) -> Result<HttpResponse, WebError> {
+    let error = |error, unauthorized| {
+        json_error(error, unauthorized).map(|response| response.respond_to(&http_req))
+    };
+    let settings = state.settings();
+    if !settings.pushed_authorization.enabled {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let confidential = authenticate_client(&auth_request, &state).filter(|client_id| {
+        state
+            .registrar()
+            .client(client_id)
+            .is_some_and(|client| client.auth_method() != ClientAuthMethod::None)
+    });
+    let client_id = match confidential {
+        Some(client_id) => client_id,
+        None => return error("invalid_client", true),
+    };
+    let mut parameters = match auth_request.body() {
+        Some(body) if body.unique_value("request_uri").is_none() => body.clone(),
+        _ => return error("invalid_request", false),
+    };
+    // A request object is verified when it is pushed
+    let request = parameters.unique_value("request").map(|r| r.to_string());
+    let signed = request.is_some();
+    if let Some(request) = request {
+        parameters = match apply_request_object(&client_id, &request, &state) {
+            Some(parameters) => parameters,
+            None => return error("invalid_request_object", false),
+        };
+    } else if state
+        .registrar()
+        .client(&client_id)
+        .is_some_and(|client| client.require_request_object)
+    {
+        return error("invalid_request_object", false);
+    }
+
+    // Check the request like the authorization endpoint does, so errors are reported to the client
+    if parameters.unique_value("response_type").as_deref() != Some("code") {
+        return error("unsupported_response_type", false);
+    }
+    let redirect_uri = match parameters
+        .unique_value("redirect_uri")
+        .map(|uri| uri.parse::<ExactUrl>())
+        .transpose()
+    {
+        Ok(redirect_uri) => redirect_uri,
+        Err(_) => return error("invalid_request", false),
+    };
+    let bound = state.registrar().bound_redirect(ClientUrl {
+        client_id: Cow::Borrowed(&client_id),
+        redirect_uri: redirect_uri.map(Cow::Owned),
+    });
+    if bound.is_err() {
+        return error("invalid_request", false);
+    }
+
+    // The credentials of the client are not kept
+    for name in ["client_secret", "client_assertion", "client_assertion_type"] {
+        if parameters.unique_value(name).is_some() {
+            parameters.insert_or_poison(name.into(), Cow::Borrowed(""));
+        }
+    }
+    if parameters.unique_value("client_id").is_none() {
+        parameters.insert_or_poison("client_id".into(), Cow::Owned(client_id.clone()));
+    }
+    let request_uri = state.pushed_requests().push(&client_id, parameters, signed);
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "request_uri": request_uri,
+        "expires_in": settings.pushed_authorization.expires_in,
+    })))
+}
+
+/// Start a device authorization request (RFC 8628) and return the device and user code.
+pub async fn device_authorization(
     (auth_request, state): (OAuthRequest, web::Data<State>),
 ) -> Result<OAuthResponse, WebError> {
-    let endpoint = state.endpoint();
-    RefreshFlow::prepare(endpoint)?
-        .execute(auth_request)
-        .map_err(WebError::from)
+    if !state.settings().device_flow.enabled {
+        return json_error("unsupported_grant_type", false);
+    }
+    let client_id = match authenticate_client(&auth_request, &state) {
+        Some(client_id) => client_id,
+        None => return json_error("invalid_client", true),
+    };
+    if !allows_grant_type(Some(&client_id), DEVICE_CODE_GRANT_TYPE, &state) {
+        return json_error("unauthorized_client", false);
+    }
+    let scope = match auth_request
+        .body()
+        .and_then(|body| body.unique_value("scope"))
+        .map(|scope| scope.parse())
+        .transpose()
+    {
+        Ok(scope) => scope,
+        Err(_) => return json_error("invalid_scope", false),
+    };
+
+    let pre_grant = {
+        let registrar = state.registrar();
+        let bound = registrar.bound_redirect(ClientUrl {
+            client_id: Cow::Owned(client_id),
+            redirect_uri: None,
+        });
+        match bound.and_then(|bound| registrar.negotiate(bound, scope)) {
+            Ok(pre_grant) => pre_grant,
+            Err(_) => return json_error("invalid_client", true),
+        }
+    };
+
+    let settings = state.settings();
+    let settings = &settings.device_flow;
+    let mut device_codes = state.device_codes();
+    let (device_code, authorization) = device_codes.start(pre_grant);
+    let user_code = format_user_code(&authorization.user_code);
+
+    let mut verification_uri_complete = url::Url::parse(&settings.verification_uri)
+        .map_err(|e| WebError::InternalError(Some(e.to_string())))?;
+    verification_uri_complete
+        .query_pairs_mut()
+        .append_pair("user_code", &user_code);
+
+    let body = serde_json::json!({
+        "device_code": device_code,
+        "user_code": user_code,
+        "verification_uri": settings.verification_uri,
+        "verification_uri_complete": verification_uri_complete.to_string(),
+        "expires_in": (authorization.until - Utc::now()).num_seconds(),
+        "interval": settings.interval,
+    });
+    let mut response = OAuthResponse::ok();
+    response.body_json(&body.to_string())?;
+    Ok(response)
 }
 
-fn verify_token(token: &str, settings: &Settings) -> Result<serde_json::Value, WebError> {
-    let key = settings
-        .client
-        .token_verification
-        .create_decoding_key()
-        .map_err(|e| {
+fn device_token(
+    auth_request: &OAuthRequest,
+    http_req: &HttpRequest,
+    state: &State,
+) -> Result<OAuthResponse, WebError> {
+    if !state.settings().device_flow.enabled {
+        return json_error("unsupported_grant_type", false);
+    }
+    let client_id = match authenticate_client(auth_request, state) {
+        Some(client_id) => client_id,
+        None => return json_error("invalid_client", true),
+    };
+    let device_code = match auth_request
+        .body()
+        .and_then(|body| body.unique_value("device_code"))
+    {
+        Some(device_code) => device_code.to_string(),
+        None => return json_error("invalid_request", false),
+    };
+
+    let approved = state.device_codes().poll(&device_code, &client_id);
+    match approved {
+        Ok(approved) => {
+            let grant = Grant {
+                owner_id: approved.owner_id,
+                client_id,
+                scope: approved.pre_grant.scope.clone(),
+                redirect_uri: approved.pre_grant.redirect_uri.into_url(),
+                // Replaced by the issuer with the configured token lifetime
+                until: Utc::now(),
+                extensions: approved.extensions,
+            };
+            let source_ip = source_ip(http_req, state);
+            let mut issuer = state.issuer();
+            issuer.set_source_ip(source_ip);
+            let issued = issuer
+                .issue(grant)
+                .map_err(|_| WebError::InternalError(Some("Could not issue token".to_string())))?;
+            let token_response = TokenResponse {
+                access_token: Some(issued.token),
+                refresh_token: issued.refresh,
+                token_type: Some("bearer".to_string()),
+                expires_in: Some((issued.until - Utc::now()).num_seconds()),
+                scope: Some(approved.pre_grant.scope.to_string()),
+                error: None,
+            };
+            let body = serde_json::to_string(&token_response)
+                .map_err(|e| WebError::InternalError(Some(e.to_string())))?;
+            let mut response = OAuthResponse::ok();
+            response.body_json(&body)?;
+            Ok(response)
+        }
+        Err(e) => {
+            debug!("Device code can not be exchanged for token: {:?}", e);
+            json_error(e.error_code(), false)
+        }
+    }
+}
+
+/// Exchange a valid token for a new token with a different audience and a
+/// possibly reduced scope (RFC 8693).
+fn token_exchange(
+    auth_request: &OAuthRequest,
+    http_req: &HttpRequest,
+    state: &State,
+) -> Result<OAuthResponse, WebError> {
+    let settings = state.settings();
+    let settings = &settings.token_exchange;
+    if !settings.enabled {
+        return json_error("unsupported_grant_type", false);
+    }
+    let client_id = match authenticate_client(auth_request, state) {
+        Some(client_id) => client_id,
+        None => return json_error("invalid_client", true),
+    };
+    let param = |name: &str| {
+        auth_request
+            .body()
+            .and_then(|body| body.unique_value(name))
+            .map(|value| value.to_string())
+    };
+
+    let subject_token = match (param("subject_token"), param("subject_token_type")) {
+        (Some(token), Some(token_type))
+            if token_type == ACCESS_TOKEN_TYPE || token_type == JWT_TOKEN_TYPE =>
+        {
+            token
+        }
+        _ => return json_error("invalid_request", false),
+    };
+    if let Some(requested_token_type) = param("requested_token_type") {
+        if requested_token_type != ACCESS_TOKEN_TYPE && requested_token_type != JWT_TOKEN_TYPE {
+            return json_error("invalid_request", false);
+        }
+    }
+    let audience = match param("audience") {
+        Some(audience) if settings.is_allowed(&client_id, &audience) => audience,
+        Some(audience) => {
+            debug!(
+                "Client {} is not allowed to exchange tokens for audience {}",
+                client_id, audience
+            );
+            return json_error("invalid_target", false);
+        }
+        None => return json_error("invalid_request", false),
+    };
+    let mut claims = match verify_token(&subject_token, state) {
+        Ok(serde_json::Value::Object(claims)) => claims,
+        _ => return json_error("invalid_request", false),
+    };
+
+    // The subject token must have been issued to the client or be intended
+    // for it, unless the client may exchange the tokens of the other client
+    let subject_client = claims.get("client_id").and_then(|c| c.as_str());
+    let intended_for_client = match claims.get("aud") {
+        Some(serde_json::Value::String(aud)) => aud == &client_id,
+        Some(serde_json::Value::Array(aud)) => aud.iter().any(|aud| aud == client_id.as_str()),
+        _ => false,
+    };
+    if subject_client != Some(client_id.as_str())
+        && !intended_for_client
+        && !subject_client.is_some_and(|subject_client| {
+            settings.allows_subject_client(&client_id, subject_client)
+        })
+    {
+        debug!(
+            "Client {} is not allowed to exchange tokens of client {:?}",
+            client_id, subject_client
+        );
+        return json_error("invalid_request", false);
+    }
+
+    // The requested scope must not exceed the scope of the subject token
+    let subject_scope = match claims.get("scope").and_then(|scope| scope.as_str()) {
+        Some(scope) => match scope.parse::<Scope>() {
+            Ok(scope) => Some(scope),
+            Err(_) => return json_error("invalid_request", false),
+        },
+        None => None,
+    };
+    let scope = match (param("scope"), subject_scope) {
+        (Some(requested), Some(subject_scope)) => match requested.parse::<Scope>() {
+            Ok(requested) if subject_scope.priviledged_to(&requested) => Some(requested),
+            _ => return json_error("invalid_scope", false),
+        },
+        (Some(_), None) => return json_error("invalid_scope", false),
+        (None, subject_scope) => subject_scope,
+    };
+
+    // The new token must not outlive the subject token
+    let mut exp = (Utc::now() + Duration::seconds(settings.lifetime)).timestamp();
+    if let Some(subject_exp) = claims.get("exp").and_then(|exp| exp.as_i64()) {
+        exp = exp.min(subject_exp);
+    }
+    claims.insert("exp".to_string(), exp.into());
+    claims.insert("aud".to_string(), audience.into());
+    claims.insert("jti".to_string(), generate_jti().into());
+    claims.insert("client_id".to_string(), client_id.clone().into());
+    match &scope {
+        Some(scope) => claims.insert("scope".to_string(), scope.to_string().into()),
+        None => claims.remove("scope"),
+    };
+    // Record the client as the current actor and keep any previous actors nested inside
+    let mut act = serde_json::Map::new();
+    act.insert("sub".to_string(), client_id.clone().into());
+    if let Some(previous_act) = claims.remove("act") {
+        act.insert("act".to_string(), previous_act);
+    }
+    claims.insert("act".to_string(), act.into());
+
+    let token = state.issuer().sign(&claims).map_err(|e| {
+        error!("Could not sign exchanged token: {}", e);
+        WebError::InternalError(Some("Could not issue token".to_string()))
+    })?;
+    let claim = |name: &str| claims[name].as_str().map(|value| value.to_string());
+    state.audit.record(&AuditEvent {
+        client_id: Some(client_id),
+        sub: claim("sub"),
+        source_ip: source_ip(http_req, state),
+        jti: claim("jti"),
+        ..AuditEvent::new(AuditEventKind::Token, "issued")
+    });
+    let mut body = serde_json::json!({
+        "access_token": token,
+        "issued_token_type": ACCESS_TOKEN_TYPE,
+        "token_type": "bearer",
+        "expires_in": exp - Utc::now().timestamp(),
+    });
+    if let Some(scope) = scope {
+        body["scope"] = scope.to_string().into();
+    }
+    let mut response = OAuthResponse::ok();
+    response.body_json(&body.to_string())?;
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub struct DeviceParams {
+    user_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DeviceDecisionParams {
+    user_code: String,
+    decision: String,
+    csrf_token: Option<String>,
+}
+
+/// Render the device page with the custom `device` template or the built-in one.
+fn render_device_page(
+    status: StatusCode,
+    http_req: &HttpRequest,
+    state: &State,
+    variables: serde_json::Value,
+) -> Result<HttpResponse, WebError> {
+    match state.pages.render_html(
+        "device",
+        include_str!("device-verification.html"),
+        http_req,
+        variables,
+    ) {
+        Ok(page) => Ok(HttpResponseBuilder::new(status)
+            .content_type("text/html; charset=utf-8")
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .body(page)),
+        Err(e) => {
+            error!("Could not render device page: {}", e);
+            Ok(state
+                .pages
+                .render(Page::Error, "server_error", None, http_req))
+        }
+    }
+}
+
+/// Show the page where the user can enter and confirm the user code of a device.
+pub async fn device(
+    (http_req, params, state): (HttpRequest, web::Query<DeviceParams>, web::Data<State>),
+) -> Result<HttpResponse, WebError> {
+    if !state.settings().device_flow.enabled {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let client_id = params.user_code.as_ref().and_then(|user_code| {
+        state
+            .device_codes()
+            .find_by_user_code(user_code)
+            .map(|a| a.pre_grant.client_id.clone())
+    });
+    let identity = identify(&http_req, client_id.as_deref(), &state);
+    render_device_page(
+        StatusCode::OK,
+        &http_req,
+        &state,
+        serde_json::json!({
+            "user_code": params.user_code,
+            "client_id": client_id,
+            "csrf_token": device_csrf_token(identity.owner.as_deref(), client_id.as_deref(), &state),
+        }),
+    )
+}
+
+/// Create a token for the device page that is bound to the user and the
+/// client of the entered user code, if it is already known.
+fn device_csrf_token(owner: Option<&str>, client_id: Option<&str>, state: &State) -> String {
+    state.consents().csrf_token(
+        CsrfForm::Device,
+        owner.unwrap_or_default(),
+        client_id.unwrap_or_default(),
+    )
+}
+
+/// Approve or deny a device authorization on behalf of the user identified
+/// by the forwarded headers.
+pub async fn device_decision(
+    (http_req, params, state): (
+        HttpRequest,
+        web::Form<DeviceDecisionParams>,
+        web::Data<State>,
+    ),
+) -> Result<HttpResponse, WebError> {
+    if !state.settings().device_flow.enabled {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let client_id = match state
+        .device_codes()
+        .find_by_user_code(&params.user_code)
+        .map(|authorization| authorization.pre_grant.client_id.clone())
+    {
+        Some(client_id) => client_id,
+        None => return invalid_user_code(&params.user_code, &http_req, &state),
+    };
+    let identity = identify(&http_req, Some(&client_id), &state);
+
+    // The token is bound to the client, so a code that has been entered on the
+    // page has to be confirmed once more, after the user has seen the client.
+    let csrf_token = params.csrf_token.as_deref().unwrap_or_default();
+    if !state.consents().verify_csrf_token(
+        csrf_token,
+        CsrfForm::Device,
+        identity.owner.as_deref().unwrap_or_default(),
+        &client_id,
+    ) {
+        if !csrf_token.is_empty() {
+            debug!(
+                "CSRF token of device page for client {} does not match",
+                client_id
+            );
+        }
+        return render_device_page(
+            StatusCode::OK,
+            &http_req,
+            &state,
+            serde_json::json!({
+                "user_code": params.user_code,
+                "client_id": client_id,
+                "csrf_token": device_csrf_token(identity.owner.as_deref(), Some(&client_id), &state),
+            }),
+        );
+    }
+
+    let decision = if params.decision == "approve" {
+        let outcome = if identity.owner.is_some() {
+            "approved"
+        } else {
+            "denied"
+        };
+        state.audit.record(&AuditEvent {
+            client_id: Some(client_id.clone()),
+            sub: identity.owner.clone(),
+            source_ip: source_ip(&http_req, &state),
+            attributes: state.audit.attributes(&identity.attributes),
+            ..AuditEvent::new(AuditEventKind::Authorize, outcome)
+        });
+        match identity.owner.clone() {
+            Some(owner_id) => DeviceStatus::Approved {
+                owner_id,
+                extensions: identity.extensions(),
+            },
+            None => {
+                state.metrics.record_denial(Denial::MissingIdentity);
+                return Ok(state.pages.render(
+                    Page::Denied,
+                    "access_denied",
+                    Some(&client_id),
+                    &http_req,
+                ));
+            }
+        }
+    } else {
+        state.audit.record(&AuditEvent {
+            client_id: Some(client_id),
+            source_ip: source_ip(&http_req, &state),
+            ..AuditEvent::new(AuditEventKind::Authorize, "denied")
+        });
+        DeviceStatus::Denied
+    };
+    let approved = matches!(decision, DeviceStatus::Approved { .. });
+
+    if state.device_codes().decide(&params.user_code, decision) {
+        let message = if approved {
+            "The device has been logged in. You can close this page and return to your device."
+        } else {
+            "The login of the device has been denied."
+        };
+        render_device_page(
+            StatusCode::OK,
+            &http_req,
+            &state,
+            serde_json::json!({ "message": message, "finished": true }),
+        )
+    } else {
+        invalid_user_code(&params.user_code, &http_req, &state)
+    }
+}
+
+fn invalid_user_code(
+    user_code: &str,
+    http_req: &HttpRequest,
+    state: &State,
+) -> Result<HttpResponse, WebError> {
+    render_device_page(
+        StatusCode::BAD_REQUEST,
+        http_req,
+        state,
+        serde_json::json!({
+            "message": "The code is invalid or has expired.",
+            "user_code": user_code,
+        }),
+    )
+}
+
+fn verify_token(token: &str, state: &State) -> Result<serde_json::Value, WebError> {
+    decode_token(token, state, true)
+}
+
+/// Verify the signature of a token issued by this server and return its
+/// claims, optionally ignoring if it is expired.
+///
+/// Tokens signed with keys used before key rotations are accepted, too, until
+/// all tokens signed with them have expired.
+fn decode_token(
+    token: &str,
+    state: &State,
+    check_expiry: bool,
+) -> Result<serde_json::Value, WebError> {
+    let mut last_error = None;
+    for verification in state.verification_keys() {
+        let key = verification.create_decoding_key().map_err(|e| {
             error!("Could not create decoding key to verify token: {}", e);
             WebError::InternalError(Some(
                 "Could not verify token due to internal error".to_string(),
             ))
         })?;
 
-    let validation =
-        jsonwebtoken::Validation::new(settings.client.token_verification.as_algorithm());
+        let mut validation = jsonwebtoken::Validation::new(verification.as_algorithm());
+        validation.validate_exp = check_expiry;
 
-    match jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation) {
-        Ok(token) => Ok(token.claims),
-        Err(err) => {
-            debug!("{}", err);
-            Err(WebError::Authorization)
+        match jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation) {
+            Ok(token) => return Ok(token.claims),
+            Err(err) => last_error = Some(err),
         }
     }
+    if let Some(err) = last_error {
+        debug!("{}", err);
+    }
+    Err(WebError::Authorization)
 }
 
 pub async fn userinfo(
     (req, state): (HttpRequest, web::Data<State>),
 ) -> Result<HttpResponse, WebError> {
+    let mut event = AuditEvent {
+        source_ip: source_ip(&req, &state),
+        ..AuditEvent::new(AuditEventKind::Userinfo, "missing_token")
+    };
     // Extract the Authorization header with the bearer token
     if let Some(auth_header) = req.headers().get("Authorization") {
         // Parse header
@@ -163,24 +1290,307 @@ pub async fn userinfo(
             if auth_header.starts_with("bearer") || auth_header.starts_with("Bearer") {
                 // Parse and verify token
                 let token = auth_header[6..auth_header.len()].trim();
-                return match verify_token(token, &state.settings) {
+                let response = match verify_token(token, &state) {
                     // Use the verified claim
-                    Ok(claim) => Ok(HttpResponseBuilder::new(StatusCode::OK)
-                        .content_type("application/json")
-                        .body(claim.to_string())
-                        .into()),
+                    Ok(claim) => {
+                        event.outcome = "success".to_string();
+                        event.sub = claim["sub"].as_str().map(|sub| sub.to_string());
+                        event.jti = claim["jti"].as_str().map(|jti| jti.to_string());
+                        HttpResponseBuilder::new(StatusCode::OK)
+                            .content_type("application/json")
+                            .body(claim.to_string())
+                    }
                     // If a token was given but invalid, report an error
                     Err(e) => {
                         debug!("Invalid request to userinfo endpoint: {}", e);
-                        Ok(HttpResponse::Forbidden().into())
+                        event.outcome = "invalid_token".to_string();
+                        HttpResponse::Forbidden().into()
                     }
                 };
+                state.audit.record(&event);
+                return Ok(response);
             }
         }
     }
 
+    state.audit.record(&event);
     Ok(HttpResponse::Unauthorized().into())
 }
 
+/// RP-initiated logout as defined by OpenID Connect, which revokes the refresh
+/// tokens of the user and redirects them back to the client.
+pub async fn logout(
+    (auth_request, http_req, state): (OAuthRequest, HttpRequest, web::Data<State>),
+) -> Result<HttpResponse, WebError> {
+    let param = |name: &str| {
+        auth_request
+            .query()
+            .and_then(|query| query.unique_value(name))
+            .or_else(|| auth_request.body().and_then(|body| body.unique_value(name)))
+            .map(|value| value.to_string())
+    };
+    let invalid_request = || {
+        Ok(state
+            .pages
+            .render(Page::InvalidClient, "invalid_request", None, &http_req))
+    };
+
+    // A token issued before, which may have expired already, identifies the
+    // user and the client, which must match the given client
+    let hint = match param("id_token_hint") {
+        Some(hint) => match decode_token(&hint, &state, false) {
+            Ok(claims) => Some(claims),
+            Err(_) => return invalid_request(),
+        },
+        None => None,
+    };
+    let hint_client_id = hint
+        .as_ref()
+        .and_then(|claims| claims["client_id"].as_str())
+        .map(|client_id| client_id.to_string());
+    let client_id = match (param("client_id"), hint_client_id) {
+        (Some(client_id), hint_client_id)
+            if hint.is_some() && hint_client_id.as_ref() != Some(&client_id) =>
+        {
+            debug!(
+                "The token for logging out has not been issued to {}",
+                client_id
+            );
+            return invalid_request();
+        }
+        (client_id, hint_client_id) => client_id.or(hint_client_id),
+    };
+
+    // The redirect URI must be registered for the client
+    let redirect_uri = match param("post_logout_redirect_uri") {
+        Some(redirect_uri) => {
+            let registered = client_id.as_deref().is_some_and(|client_id| {
+                state.registrar().client(client_id).is_some_and(|client| {
+                    client
+                        .post_logout_redirect_uris
+                        .iter()
+                        .any(|uri| uri == &redirect_uri)
+                })
+            });
+            let mut redirect_uri = match url::Url::parse(&redirect_uri) {
+                Ok(redirect_uri) if registered => redirect_uri,
+                _ => return invalid_request(),
+            };
+            if let Some(logout_state) = param("state") {
+                redirect_uri
+                    .query_pairs_mut()
+                    .append_pair("state", &logout_state);
+            }
+            Some(redirect_uri)
+        }
+        None => None,
+    };
+
+    let owner = match hint {
+        Some(claims) => claims["sub"].as_str().map(|sub| sub.to_string()),
+        None => {
+            // Without a token, the user identified by the proxy has to confirm
+            // the logout, so other sites can not log them out
+            let owner = identify(&http_req, client_id.as_deref(), &state).owner;
+            let csrf_owner = owner.as_deref().unwrap_or_default();
+            let csrf_client_id = client_id.as_deref().unwrap_or_default();
+            let confirmed = http_req.method() == actix_web::http::Method::POST
+                && param("csrf_token").is_some_and(|csrf_token| {
+                    state.consents().verify_csrf_token(
+                        &csrf_token,
+                        CsrfForm::Logout,
+                        csrf_owner,
+                        csrf_client_id,
+                    )
+                });
+            if !confirmed {
+                let parameters: serde_json::Map<String, serde_json::Value> =
+                    ["client_id", "post_logout_redirect_uri", "state"]
+                        .iter()
+                        .filter_map(|name| {
+                            param(name).map(|value| (name.to_string(), value.into()))
+                        })
+                        .collect();
+                let variables = serde_json::json!({
+                    "client_id": client_id,
+                    "parameters": parameters,
+                    "csrf_token": state.consents().csrf_token(CsrfForm::Logout, csrf_owner, csrf_client_id),
+                });
+                return Ok(
+                    match state.pages.render_html(
+                        "logout",
+                        include_str!("logout.html"),
+                        &http_req,
+                        variables,
+                    ) {
+                        Ok(page) => HttpResponse::Ok()
+                            .content_type("text/html; charset=utf-8")
+                            .insert_header((header::CACHE_CONTROL, "no-store"))
+                            .body(page),
+                        Err(e) => {
+                            error!("Could not render logout page: {}", e);
+                            state
+                                .pages
+                                .render(Page::Error, "server_error", None, &http_req)
+                        }
+                    },
+                );
+            }
+            owner
+        }
+    };
+
+    if let Some(owner) = &owner {
+        let revoked = state.issuer().revoke(owner, client_id.as_deref());
+        debug!("Revoked {} refresh tokens of {} on logout", revoked, owner);
+    }
+    state.audit.record(&AuditEvent {
+        client_id: client_id.clone(),
+        sub: owner,
+        source_ip: source_ip(&http_req, &state),
+        ..AuditEvent::new(AuditEventKind::Logout, "logged_out")
+    });
+
+    // Also end the session at the Shibboleth SP, which then redirects to the client
+    let location = match (&state.settings().logout.shibboleth_logout_url, redirect_uri) {
+        (Some(shibboleth_logout_url), Some(redirect_uri)) => {
+            match shibboleth_logout_location(shibboleth_logout_url, &redirect_uri) {
+                Ok(location) => location,
+                Err(e) => {
+                    error!("Invalid Shibboleth logout URL: {}", e);
+                    return Ok(state
+                        .pages
+                        .render(Page::Error, "server_error", None, &http_req));
+                }
+            }
+        }
+        (Some(shibboleth_logout_url), None) => shibboleth_logout_url.clone(),
+        (None, Some(redirect_uri)) => redirect_uri.to_string(),
+        (None, None) => {
+            return Ok(state
+                .pages
+                .render(Page::LoggedOut, "", client_id.as_deref(), &http_req))
+        }
+    };
+    Ok(HttpResponse::Found()
+        .append_header(("Location", location))
+        .finish())
+}
+
+/// Add the URI the user is redirected to afterwards to the logout handler of
+/// the Shibboleth SP, which may be given as a path on the same server.
+fn shibboleth_logout_location(
+    logout_url: &str,
+    redirect_uri: &url::Url,
+) -> Result<String, url::ParseError> {
+    let (mut location, relative) = match url::Url::parse(logout_url) {
+        Ok(location) => (location, false),
+        Err(url::ParseError::RelativeUrlWithoutBase) => (
+            url::Url::parse("http://localhost/")?.join(logout_url)?,
+            true,
+        ),
+        Err(e) => return Err(e),
+    };
+    location
+        .query_pairs_mut()
+        .append_pair("return", redirect_uri.as_str());
+    if relative {
+        Ok(location[url::Position::BeforePath..].to_string())
+    } else {
+        Ok(location.to_string())
+    }
+}
+
+/// Liveness probe, which succeeds as long as the server handles requests.
+pub async fn health() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness probe, which checks that tokens can actually be issued.
+pub async fn ready(state: web::Data<State>) -> HttpResponse {
+    let signing_key = state
+        .settings()
+        .client
+        .token_verification
+        .create_encoding_key()
+        .map(|_| ());
+    let template = state.issuer().check_template();
+    // Refresh tokens are only kept in memory, so the store is always reachable
+    let refresh_store: Result<(), RuntimeError> = Ok(());
+
+    let mut ready = true;
+    let mut checks = serde_json::Map::new();
+    for (name, result) in [
+        ("signing_key", signing_key),
+        ("template", template),
+        ("refresh_store", refresh_store),
+    ] {
+        let status = match result {
+            Ok(()) => "ok",
+            Err(e) => {
+                error!("Readiness check {} failed: {}", name, e);
+                ready = false;
+                "failed"
+            }
+        };
+        checks.insert(name.to_string(), status.into());
+    }
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    HttpResponseBuilder::new(status).json(serde_json::json!({
+        "status": if ready { "ready" } else { "not ready" },
+        "checks": checks,
+    }))
+}
+
+/// Report the version and the signing algorithm, but no secrets.
+pub async fn version(state: web::Data<State>) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "algorithm": format!("{:?}", state.settings().client.token_verification.as_algorithm()),
+    }))
+}
+
+/// Metrics in the Prometheus text format.
+pub async fn metrics(state: web::Data<State>) -> HttpResponse {
+    if !state.settings().metrics.enabled {
+        return HttpResponse::NotFound().finish();
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(state.metrics.render(&state.store_sizes()))
+}
+
+/// Middleware that records the outcome of each request by its route in the metrics.
+pub fn record_request<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let metrics = req
+        .app_data::<web::Data<State>>()
+        .map(|state| state.metrics.clone());
+    let endpoint = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    let response = srv.call(req);
+    async move {
+        let response = response.await?;
+        if let Some(metrics) = metrics {
+            metrics.record_request(&endpoint, response.status().as_u16());
+        }
+        Ok(response)
+    }
+}
+
 #[cfg(test)]
+// The original tests pass the service mutably and compare with boolean literals
+#[allow(clippy::unnecessary_mut_passed, clippy::bool_assert_comparison)]
 mod tests;
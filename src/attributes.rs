@@ -0,0 +1,199 @@
+use oxide_auth::primitives::grant::Value;
+use serde::{Deserialize, Serialize};
+
+use crate::settings::{HeaderEscaping, HeaderOutput, HeaderSettings};
+
+/// The value of an identity attribute, which can either be a single string or a list of strings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AttributeValue {
+    Single(String),
+    List(Vec<String>),
+}
+
+impl AttributeValue {
+    /// Parse a raw header value according to the settings for this header.
+    pub fn parse(raw: &str, settings: Option<&HeaderSettings>) -> AttributeValue {
+        let settings = match settings {
+            Some(settings) => settings,
+            None => return AttributeValue::Single(raw.to_string()),
+        };
+
+        let mut values = match &settings.delimiter {
+            Some(delimiter) if !delimiter.is_empty() => {
+                split_values(raw, delimiter, &settings.escape)
+            }
+            _ => vec![unescape(raw, &settings.escape)],
+        };
+        if settings.deduplicate {
+            let mut seen = std::collections::HashSet::new();
+            values.retain(|v| seen.insert(v.clone()));
+        }
+
+        match settings.output {
+            HeaderOutput::List => AttributeValue::List(values),
+            HeaderOutput::String => {
+                let delimiter = settings.delimiter.as_deref().unwrap_or_default();
+                AttributeValue::Single(values.join(delimiter))
+            }
+        }
+    }
+
+    /// Encode the value so it can be stored as grant extension.
+    pub fn to_extension(&self) -> Value {
+        Value::public(serde_json::to_string(self).ok())
+    }
+
+    /// Decode a value that has been stored as grant extension.
+    ///
+    /// Values that have not been encoded as JSON are treated as a single string.
+    pub fn from_extension(value: &str) -> AttributeValue {
+        serde_json::from_str(value).unwrap_or_else(|_| AttributeValue::Single(value.to_string()))
+    }
+}
+
+impl From<AttributeValue> for serde_json::Value {
+    fn from(value: AttributeValue) -> Self {
+        match value {
+            AttributeValue::Single(value) => value.into(),
+            AttributeValue::List(values) => values.into(),
+        }
+    }
+}
+
+/// Checks if a header name matches a pattern, which can contain `*` as
+/// wildcard for any number of characters. Header names are compared
+/// case-insensitively.
+pub fn glob_matches(pattern: &str, header: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let header = header.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match header.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    if parts.is_empty() {
+        // No wildcard at all
+        return rest.is_empty();
+    }
+    let (last, middle) = parts.split_last().unwrap_or((&"", &[]));
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Converts a header name into an identifier that can be used as variable in
+/// the token template, e.g. `Shib-Identity-Provider` becomes `shib_identity_provider`.
+pub fn normalize_variable_name(header: &str) -> String {
+    header
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Split the raw value at each unescaped delimiter and remove the escaping
+/// from the resulting values. Empty values are ignored.
+fn split_values(raw: &str, delimiter: &str, escape: &HeaderEscaping) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut current = String::new();
+    let mut rest = raw;
+    while let Some(c) = rest.chars().next() {
+        if *escape == HeaderEscaping::Backslash && c == '\\' {
+            // Take the next character literally
+            let mut chars = rest[1..].chars();
+            if let Some(escaped) = chars.next() {
+                current.push(escaped);
+                rest = chars.as_str();
+            } else {
+                current.push(c);
+                rest = "";
+            }
+        } else if let Some(after) = rest.strip_prefix(delimiter) {
+            result.push(std::mem::take(&mut current));
+            rest = after;
+        } else {
+            current.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    result.push(current);
+    result.retain(|v| !v.is_empty());
+    result
+}
+
+fn unescape(raw: &str, escape: &HeaderEscaping) -> String {
+    match escape {
+        HeaderEscaping::None => raw.to_string(),
+        HeaderEscaping::Backslash => {
+            let mut result = String::with_capacity(raw.len());
+            let mut chars = raw.chars();
+            while let Some(c) = chars.next() {
+                if c == '\\' {
+                    result.push(chars.next().unwrap_or(c));
+                } else {
+                    result.push(c);
+                }
+            }
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("Shib-*", "shib-identity-provider"));
+        assert!(glob_matches("x-admin", "X-Admin"));
+        assert!(glob_matches("*-Provider", "Shib-Identity-Provider"));
+        assert!(glob_matches("Shib-*-Provider", "Shib-Identity-Provider"));
+        assert!(!glob_matches("Shib-*", "X-Shib-Identity-Provider"));
+        assert!(!glob_matches("x-admin", "x-admin-role"));
+        assert!(!glob_matches("Shib-*-Provider", "Shib-Provider"));
+    }
+
+    #[test]
+    fn test_parse_shibboleth_values() {
+        let settings = HeaderSettings::default();
+        assert_eq!(
+            AttributeValue::List(vec![
+                "urn:mace:dir:entitlement:common-lib-terms".to_string(),
+                "a;b".to_string(),
+                "urn:mace:dir:entitlement:common-lib-terms".to_string(),
+            ]),
+            AttributeValue::parse(
+                r"urn:mace:dir:entitlement:common-lib-terms;a\;b;;urn:mace:dir:entitlement:common-lib-terms",
+                Some(&settings)
+            )
+        );
+
+        let settings = HeaderSettings {
+            deduplicate: true,
+            output: HeaderOutput::String,
+            ..Default::default()
+        };
+        assert_eq!(
+            AttributeValue::Single("a;b".to_string()),
+            AttributeValue::parse("a;b;a", Some(&settings))
+        );
+
+        assert_eq!(
+            AttributeValue::Single(r"a\;b".to_string()),
+            AttributeValue::parse(r"a\;b", None)
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use std::{collections::HashMap, fs::OpenOptions, io::Write, sync::Mutex};
+
+use chrono::{SecondsFormat, Utc};
+use log::error;
+use ring::digest;
+use serde::Serialize;
+
+use crate::{attributes::AttributeValue, errors::StartupError, settings::Audit};
+
+/// The kind of authentication event.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventKind {
+    /// The user approved or denied the authorization of a client
+    Authorize,
+    /// An access token was requested at the token endpoint
+    Token,
+    /// An access token was requested with a refresh token
+    Refresh,
+    Userinfo,
+    /// The user logged out and their refresh tokens were revoked
+    Logout,
+    /// An administrator revoked the refresh tokens of a user
+    Revoke,
+}
+
+/// A single line of the audit log.
+#[derive(Debug, Serialize)]
+pub struct AuditEvent {
+    pub timestamp: String,
+    pub event: AuditEventKind,
+    pub outcome: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(skip_serializing_if = "serde_json::Map::is_empty")]
+    pub attributes: serde_json::Map<String, serde_json::Value>,
+}
+
+impl AuditEvent {
+    pub fn new(event: AuditEventKind, outcome: &str) -> AuditEvent {
+        AuditEvent {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            event,
+            outcome: outcome.to_string(),
+            client_id: None,
+            sub: None,
+            source_ip: None,
+            jti: None,
+            attributes: serde_json::Map::new(),
+        }
+    }
+}
+
+/// Writes authentication events as JSON lines to a file or stdout.
+pub struct AuditLog {
+    output: Option<Mutex<Box<dyn Write + Send>>>,
+    hash_attributes: Vec<String>,
+    exclude_attributes: Vec<String>,
+}
+
+impl AuditLog {
+    pub fn new(settings: &Audit) -> Result<AuditLog, StartupError> {
+        let output: Option<Box<dyn Write + Send>> = if !settings.enabled {
+            None
+        } else if let Some(file) = &settings.file {
+            let file = OpenOptions::new().create(true).append(true).open(file)?;
+            Some(Box::new(file))
+        } else {
+            Some(Box::new(std::io::stdout()))
+        };
+        Ok(AuditLog {
+            output: output.map(Mutex::new),
+            hash_attributes: settings.hash_attributes.clone(),
+            exclude_attributes: settings.exclude_attributes.clone(),
+        })
+    }
+
+    pub fn record(&self, event: &AuditEvent) {
+        if let Some(output) = &self.output {
+            let line = match serde_json::to_string(event) {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("Could not serialize audit event: {}", e);
+                    return;
+                }
+            };
+            let mut output = output.lock().unwrap();
+            if let Err(e) = writeln!(output, "{}", line).and_then(|_| output.flush()) {
+                error!("Could not write audit event: {}", e);
+            }
+        }
+    }
+
+    /// Convert the attributes for the audit log, hashing or leaving out the
+    /// configured attributes.
+    pub fn attributes(
+        &self,
+        attributes: &HashMap<String, AttributeValue>,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        let matches =
+            |names: &[String], name: &str| names.iter().any(|n| n.eq_ignore_ascii_case(name));
+        attributes
+            .iter()
+            .filter(|(name, _)| !matches(&self.exclude_attributes, name))
+            .map(|(name, value)| {
+                let value = if matches(&self.hash_attributes, name) {
+                    match value {
+                        AttributeValue::Single(v) => AttributeValue::Single(hash(v)),
+                        AttributeValue::List(values) => {
+                            AttributeValue::List(values.iter().map(|v| hash(v)).collect())
+                        }
+                    }
+                } else {
+                    value.clone()
+                };
+                (name.to_string(), value.into())
+            })
+            .collect()
+    }
+}
+
+/// Hex encoded SHA-256 hash of a value.
+fn hash(value: &str) -> String {
+    digest::digest(&digest::SHA256, value.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_attributes() {
+        let log = AuditLog::new(&Audit {
+            hash_attributes: vec!["mail".to_string()],
+            exclude_attributes: vec!["eduPersonTargetedID".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        let mut attributes = HashMap::new();
+        attributes.insert(
+            "mail".to_string(),
+            AttributeValue::Single("a@example.com".to_string()),
+        );
+        attributes.insert(
+            "eduPersonTargetedID".to_string(),
+            AttributeValue::Single("x".to_string()),
+        );
+        attributes.insert(
+            "affiliation".to_string(),
+            AttributeValue::Single("staff".to_string()),
+        );
+
+        let result = log.attributes(&attributes);
+        assert_eq!(2, result.len());
+        assert_eq!("staff", result["affiliation"]);
+        assert_eq!(
+            "08168cd80dfd534ab0f10af10f1303fe00af2d43ab5c1432360d137f8197e17a",
+            result["mail"]
+        );
+    }
+}
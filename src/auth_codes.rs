@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use oxide_auth::primitives::{
+    grant::Grant,
+    prelude::{Authorizer, RandomGenerator, TagGrant},
+};
+
+/// Keeps the issued authorization codes until they are exchanged for a token.
+///
+/// In contrast to the `AuthMap` of oxide-auth, expired codes are removed and
+/// the number of stored codes can be queried.
+pub struct AuthCodeStore {
+    codes: HashMap<String, Grant>,
+    generator: RandomGenerator,
+    usage: u64,
+}
+
+impl AuthCodeStore {
+    pub fn new() -> AuthCodeStore {
+        AuthCodeStore {
+            codes: HashMap::new(),
+            generator: RandomGenerator::new(16),
+            usage: 0,
+        }
+    }
+
+    /// The number of authorization codes that have not been used yet.
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+}
+
+impl Authorizer for AuthCodeStore {
+    fn authorize(&mut self, grant: Grant) -> Result<String, ()> {
+        let now = Utc::now();
+        self.codes.retain(|_, g| g.until > now);
+
+        let code = self.generator.tag(self.usage, &grant)?;
+        self.usage = self.usage.wrapping_add(1);
+        if self.codes.contains_key(&code) {
+            return Err(());
+        }
+        self.codes.insert(code.clone(), grant);
+        Ok(code)
+    }
+
+    fn extract(&mut self, code: &str) -> Result<Option<Grant>, ()> {
+        Ok(self.codes.remove(code))
+    }
+}
@@ -0,0 +1,158 @@
+use std::borrow::Cow;
+
+use oxide_auth::{
+    endpoint::{NormalizedParameter, QueryParameter, WebRequest},
+    frontends::simple::endpoint::Error,
+};
+use oxide_auth_actix::{OAuthRequest, OAuthResponse, WebError};
+
+use crate::settings::ClientAuthMethod;
+
+pub(crate) const JWT_BEARER_ASSERTION_TYPE: &str =
+    "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// The credentials a client sent to the token endpoint and how it sent them.
+#[derive(Debug)]
+pub struct ClientCredentials {
+    pub client_id: String,
+    pub method: ClientAuthMethod,
+    /// The secret or the signed assertion, `None` for public clients
+    pub credential: Option<String>,
+}
+
+/// The subject of an assertion, which is the ID of the client, without verifying the assertion.
+fn assertion_subject(assertion: &str) -> Option<String> {
+    let payload = assertion.split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    claims["sub"].as_str().map(|sub| sub.to_string())
+}
+
+impl ClientCredentials {
+    /// Get the credentials from the HTTP Basic authentication or the body of a request.
+    ///
+    /// A `client_id` in the body has to match the client that authenticates.
+    pub fn from_request(request: &OAuthRequest) -> Option<ClientCredentials> {
+        let param = |name: &str| {
+            request
+                .body()
+                .and_then(|body| body.unique_value(name))
+                .map(|value| value.to_string())
+        };
+        let body_client_id = param("client_id");
+
+        let credentials = if let Some(auth_header) = request.authorization_header() {
+            let encoded = auth_header
+                .strip_prefix("Basic ")
+                .or_else(|| auth_header.strip_prefix("basic "))?;
+            let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+            let (client_id, secret) = decoded.split_once(':')?;
+            ClientCredentials {
+                client_id: client_id.to_string(),
+                method: ClientAuthMethod::ClientSecretBasic,
+                credential: Some(secret.to_string()),
+            }
+        } else if let Some(assertion) = param("client_assertion") {
+            if param("client_assertion_type").as_deref() != Some(JWT_BEARER_ASSERTION_TYPE) {
+                return None;
+            }
+            ClientCredentials {
+                client_id: body_client_id
+                    .clone()
+                    .or_else(|| assertion_subject(&assertion))?,
+                method: ClientAuthMethod::PrivateKeyJwt,
+                credential: Some(assertion),
+            }
+        } else if let Some(secret) = param("client_secret") {
+            ClientCredentials {
+                client_id: body_client_id.clone()?,
+                method: ClientAuthMethod::ClientSecretPost,
+                credential: Some(secret),
+            }
+        } else {
+            ClientCredentials {
+                client_id: body_client_id.clone()?,
+                method: ClientAuthMethod::None,
+                credential: None,
+            }
+        };
+
+        if body_client_id.is_some_and(|client_id| client_id != credentials.client_id) {
+            return None;
+        }
+        Some(credentials)
+    }
+}
+
+/// A request to the token endpoint with the client credentials as HTTP Basic
+/// authentication, however the client sent them, because oxide-auth only
+/// passes those to the registrar.
+pub struct ClientAuthRequest {
+    auth: Option<String>,
+    query: Option<NormalizedParameter>,
+    body: Option<NormalizedParameter>,
+}
+
+impl ClientAuthRequest {
+    pub fn new(
+        request: &OAuthRequest,
+        credentials: Option<&ClientCredentials>,
+    ) -> ClientAuthRequest {
+        let mut body = request.body().cloned();
+        let auth = match credentials {
+            Some(ClientCredentials {
+                client_id,
+                credential: Some(credential),
+                ..
+            }) => {
+                // oxide-auth rejects requests with a client_id in the body and
+                // the header, so the one in the body is removed
+                if let Some(body) = body
+                    .as_mut()
+                    .filter(|body| body.unique_value("client_id").is_some())
+                {
+                    body.insert_or_poison("client_id".into(), Cow::Borrowed(""));
+                }
+                let basic = base64::encode(format!("{}:{}", client_id, credential));
+                Some(format!("Basic {}", basic))
+            }
+            _ => request.authorization_header().map(|auth| auth.to_string()),
+        };
+        ClientAuthRequest {
+            auth,
+            query: request.query().cloned(),
+            body,
+        }
+    }
+
+    /// Convert an error of a flow, like `WebError::from` does for an [`OAuthRequest`].
+    pub fn web_error(error: Error<ClientAuthRequest>) -> WebError {
+        match error {
+            Error::Web(e) => e,
+            Error::OAuth(e) => e.into(),
+        }
+    }
+}
+
+impl WebRequest for ClientAuthRequest {
+    type Error = WebError;
+    type Response = OAuthResponse;
+
+    fn query(&mut self) -> Result<Cow<'_, dyn QueryParameter + 'static>, Self::Error> {
+        self.query
+            .as_ref()
+            .map(|query| Cow::Borrowed(query as &dyn QueryParameter))
+            .ok_or(WebError::Query)
+    }
+
+    fn urlbody(&mut self) -> Result<Cow<'_, dyn QueryParameter + 'static>, Self::Error> {
+        self.body
+            .as_ref()
+            .map(|body| Cow::Borrowed(body as &dyn QueryParameter))
+            .ok_or(WebError::Body)
+    }
+
+    fn authheader(&mut self) -> Result<Option<Cow<'_, str>>, Self::Error> {
+        Ok(self.auth.as_deref().map(Cow::Borrowed))
+    }
+}
@@ -0,0 +1,179 @@
+use std::{collections::HashMap, io, path::PathBuf};
+
+use ring::{constant_time::verify_slices_are_equal, digest};
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::StartupError, settings};
+
+fn default_grant_types() -> Vec<String> {
+    vec!["authorization_code".to_string()]
+}
+
+fn default_response_types() -> Vec<String> {
+    vec!["code".to_string()]
+}
+
+fn default_token_endpoint_auth_method() -> String {
+    "client_secret_basic".to_string()
+}
+
+/// Metadata of a dynamically registered client as defined by RFC 7591.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientMetadata {
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    #[serde(default = "default_grant_types")]
+    pub grant_types: Vec<String>,
+    #[serde(default = "default_response_types")]
+    pub response_types: Vec<String>,
+    #[serde(default = "default_token_endpoint_auth_method")]
+    pub token_endpoint_auth_method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_name: Option<String>,
+    /// URIs the user may be redirected to after logging out (OpenID Connect RP-Initiated Logout)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_logout_redirect_uris: Vec<String>,
+}
+
+/// A dynamically registered client with the token to manage its registration.
+///
+/// Only the metadata and the credentials are stored, the configuration of
+/// the client is created from them with the current settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisteredClient {
+    pub client_id: String,
+    /// The argon2 hash of the client secret, unless it is a public client
+    pub secret: Option<String>,
+    pub metadata: ClientMetadata,
+    /// Unix timestamp of the registration
+    pub issued_at: i64,
+    /// SHA-256 hash of the registration access token
+    registration_token_hash: String,
+}
+
+fn hash_token(token: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, token.as_bytes());
+    hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl RegisteredClient {
+    pub fn new(
+        client_id: &str,
+        secret: Option<String>,
+        metadata: ClientMetadata,
+        issued_at: i64,
+        registration_token: &str,
+    ) -> RegisteredClient {
+        RegisteredClient {
+            client_id: client_id.to_string(),
+            secret,
+            metadata,
+            issued_at,
+            registration_token_hash: hash_token(registration_token),
+        }
+    }
+
+    pub fn verify_registration_token(&self, token: &str) -> bool {
+        verify_slices_are_equal(
+            hash_token(token).as_bytes(),
+            self.registration_token_hash.as_bytes(),
+        )
+        .is_ok()
+    }
+}
+
+/// Keeps the dynamically registered clients, which are read by the registrar
+/// when the server is started.
+pub struct ClientStore {
+    file: Option<PathBuf>,
+    clients: HashMap<String, RegisteredClient>,
+}
+
+impl ClientStore {
+    pub fn new(settings: &settings::Registration) -> Result<ClientStore, StartupError> {
+        let file = settings.store_file.as_ref().map(PathBuf::from);
+        let clients = match &file {
+            Some(file) if file.exists() => {
+                serde_json::from_str(&std::fs::read_to_string(file)?).map_err(io::Error::from)?
+            }
+            _ => HashMap::new(),
+        };
+        Ok(ClientStore { file, clients })
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = &RegisteredClient> {
+        self.clients.values()
+    }
+
+    pub fn get(&self, client_id: &str) -> Option<&RegisteredClient> {
+        self.clients.get(client_id)
+    }
+
+    /// Add or replace a client and save the store file, if configured.
+    pub fn insert(&mut self, client: RegisteredClient) -> io::Result<()> {
+        self.clients.insert(client.client_id.clone(), client);
+        self.save()
+    }
+
+    /// Remove a client and save the store file, if configured.
+    pub fn remove(&mut self, client_id: &str) -> io::Result<Option<RegisteredClient>> {
+        let removed = self.clients.remove(client_id);
+        self.save()?;
+        Ok(removed)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            // Write to a temporary file first, so the store is not corrupted on failures
+            let mut tmp = file.clone().into_os_string();
+            tmp.push(".tmp");
+            std::fs::write(&tmp, serde_json::to_string(&self.clients)?)?;
+            std::fs::rename(&tmp, file)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persist_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings::Registration {
+            store_file: Some(
+                dir.path()
+                    .join("clients.json")
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+        let metadata: ClientMetadata =
+            serde_json::from_str(r#"{"redirect_uris": ["https://example.com/callback"]}"#).unwrap();
+
+        let mut store = ClientStore::new(&settings).unwrap();
+        store
+            .insert(RegisteredClient::new(
+                "registered",
+                Some("$argon2-hash".to_string()),
+                metadata,
+                0,
+                "token",
+            ))
+            .unwrap();
+        // The settings of the server, like its signing key, are not stored
+        let stored = std::fs::read_to_string(settings.store_file.as_ref().unwrap()).unwrap();
+        assert!(!stored.contains("token_verification"));
+
+        let store = ClientStore::new(&settings).unwrap();
+        let registered = store.get("registered").unwrap();
+        assert_eq!(Some("$argon2-hash"), registered.secret.as_deref());
+        assert_eq!(vec!["authorization_code"], registered.metadata.grant_types);
+        assert!(registered.verify_registration_token("token"));
+        assert!(!registered.verify_registration_token("other-token"));
+    }
+}
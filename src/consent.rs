@@ -0,0 +1,172 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::PathBuf,
+};
+
+use chrono::Utc;
+use log::warn;
+use oxide_auth::primitives::scope::Scope;
+use ring::{hmac, rand::SystemRandom};
+
+use crate::{errors::StartupError, settings};
+
+/// Seconds a consent page can be submitted after it has been shown.
+const CSRF_TOKEN_LIFETIME: i64 = 600;
+
+/// The forms that are protected with a CSRF token, so a token issued for one
+/// form can not be used to submit another one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrfForm {
+    Consent,
+    Device,
+    Logout,
+}
+
+impl CsrfForm {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CsrfForm::Consent => "consent",
+            CsrfForm::Device => "device",
+            CsrfForm::Logout => "logout",
+        }
+    }
+}
+
+/// Remembers which scopes a user has consented to for each client and
+/// protects the consent form against cross-site request forgery.
+pub struct ConsentStore {
+    file: Option<PathBuf>,
+    /// Granted scopes by client ID and user
+    consents: HashMap<String, HashMap<String, HashSet<String>>>,
+    csrf_key: hmac::Key,
+}
+
+impl ConsentStore {
+    pub fn new(settings: &settings::Consent) -> Result<ConsentStore, StartupError> {
+        let file = settings.store_file.as_ref().map(PathBuf::from);
+        let consents = match &file {
+            Some(file) if file.exists() => {
+                serde_json::from_str(&std::fs::read_to_string(file)?).map_err(io::Error::from)?
+            }
+            _ => HashMap::new(),
+        };
+        let csrf_key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+            .map_err(|_| io::Error::other("Could not generate CSRF key"))?;
+        Ok(ConsentStore {
+            file,
+            consents,
+            csrf_key,
+        })
+    }
+
+    /// Checks if the user has already consented to all requested scopes for this client.
+    pub fn has_consent(&self, owner: &str, client_id: &str, scope: &Scope) -> bool {
+        self.consents
+            .get(client_id)
+            .and_then(|users| users.get(owner))
+            .is_some_and(|granted| scope.iter().all(|s| granted.contains(s)))
+    }
+
+    /// Remember the consent of the user and save it to the store file, if configured.
+    pub fn remember(&mut self, owner: &str, client_id: &str, scope: &Scope) {
+        self.consents
+            .entry(client_id.to_string())
+            .or_default()
+            .entry(owner.to_string())
+            .or_default()
+            .extend(scope.iter().map(|s| s.to_string()));
+        if let Err(e) = self.save() {
+            warn!("Could not save consent store: {}", e);
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            // Write to a temporary file first, so the store is not corrupted on failures
+            let mut tmp = file.clone().into_os_string();
+            tmp.push(".tmp");
+            std::fs::write(&tmp, serde_json::to_string(&self.consents)?)?;
+            std::fs::rename(&tmp, file)?;
+        }
+        Ok(())
+    }
+
+    fn csrf_message(issued: i64, form: CsrfForm, owner: &str, client_id: &str) -> String {
+        format!("{}\n{}\n{}\n{}", issued, form.as_str(), owner, client_id)
+    }
+
+    /// Create a token for a form that is bound to the form, the user and the client.
+    pub fn csrf_token(&self, form: CsrfForm, owner: &str, client_id: &str) -> String {
+        let issued = Utc::now().timestamp();
+        let message = Self::csrf_message(issued, form, owner, client_id);
+        let signature = hmac::sign(&self.csrf_key, message.as_bytes());
+        format!(
+            "{}.{}",
+            issued,
+            base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    pub fn verify_csrf_token(
+        &self,
+        token: &str,
+        form: CsrfForm,
+        owner: &str,
+        client_id: &str,
+    ) -> bool {
+        let (issued, signature) = match token.split_once('.') {
+            Some((issued, signature)) => (issued, signature),
+            None => return false,
+        };
+        let issued: i64 = match issued.parse() {
+            Ok(issued) => issued,
+            Err(_) => return false,
+        };
+        let signature = match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        let message = Self::csrf_message(issued, form, owner, client_id);
+        Utc::now().timestamp() - issued <= CSRF_TOKEN_LIFETIME
+            && hmac::verify(&self.csrf_key, message.as_bytes(), &signature).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consent_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings::Consent {
+            store_file: Some(
+                dir.path()
+                    .join("consent.json")
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+        };
+        let mut store = ConsentStore::new(&settings).unwrap();
+        let scope: Scope = "default-scope email".parse().unwrap();
+        assert!(!store.has_consent("user", "default", &scope));
+        store.remember("user", "default", &scope);
+        assert!(store.has_consent("user", "default", &"email".parse().unwrap()));
+        assert!(!store.has_consent("user", "default", &"admin email".parse().unwrap()));
+        assert!(!store.has_consent("other", "default", &scope));
+
+        // The consent is loaded again from the file
+        let store = ConsentStore::new(&settings).unwrap();
+        assert!(store.has_consent("user", "default", &scope));
+
+        let token = store.csrf_token(CsrfForm::Consent, "user", "default");
+        assert!(store.verify_csrf_token(&token, CsrfForm::Consent, "user", "default"));
+        assert!(!store.verify_csrf_token(&token, CsrfForm::Consent, "other", "default"));
+        assert!(!store.verify_csrf_token(&token, CsrfForm::Consent, "user", "other-client"));
+        assert!(!store.verify_csrf_token("invalid", CsrfForm::Consent, "user", "default"));
+        // The token can not be used for the other forms
+        assert!(!store.verify_csrf_token(&token, CsrfForm::Device, "user", "default"));
+        assert!(!store.verify_csrf_token(&token, CsrfForm::Logout, "user", "default"));
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use oxide_auth::primitives::{grant::Extensions, prelude::PreGrant};
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::settings::DeviceFlow;
+
+/// Characters used for user codes. Vowels and characters that are easily
+/// confused are left out, as recommended by RFC 8628.
+const USER_CODE_CHARSET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LENGTH: usize = 8;
+const DEVICE_CODE_LENGTH: usize = 40;
+/// Seconds the polling interval of a device is increased by when it polls too fast.
+const SLOW_DOWN_INCREMENT: i64 = 5;
+
+pub enum DeviceStatus {
+    Pending,
+    Approved {
+        owner_id: String,
+        extensions: Extensions,
+    },
+    Denied,
+}
+
+pub struct DeviceAuthorization {
+    pub pre_grant: PreGrant,
+    pub user_code: String,
+    pub until: DateTime<Utc>,
+    status: DeviceStatus,
+    last_poll: Option<DateTime<Utc>>,
+    /// The minimum time between two polls of the device
+    interval: Duration,
+}
+
+/// A device authorization that has been approved by the user and can be used
+/// to issue a token.
+pub struct ApprovedDevice {
+    pub pre_grant: PreGrant,
+    pub owner_id: String,
+    pub extensions: Extensions,
+}
+
+/// Reasons why polling the token endpoint with a device code did not result in a token.
+#[derive(Debug, PartialEq)]
+pub enum PollError {
+    AuthorizationPending,
+    SlowDown,
+    AccessDenied,
+    ExpiredToken,
+    InvalidGrant,
+}
+
+impl PollError {
+    /// The error code as defined in section 3.5 of RFC 8628.
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            PollError::AuthorizationPending => "authorization_pending",
+            PollError::SlowDown => "slow_down",
+            PollError::AccessDenied => "access_denied",
+            PollError::ExpiredToken => "expired_token",
+            PollError::InvalidGrant => "invalid_grant",
+        }
+    }
+}
+
+/// Formats a normalized user code with a dash in the middle, e.g. `BDFG-HJKL`.
+pub fn format_user_code(user_code: &str) -> String {
+    let (first, second) = user_code.split_at(user_code.len() / 2);
+    format!("{}-{}", first, second)
+}
+
+/// Removes all separators from a user code and converts it to upper case, so
+/// users can type it in in any form.
+pub fn normalize_user_code(user_code: &str) -> String {
+    user_code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Keeps track of pending device authorization requests by their device code.
+pub struct DeviceCodeStore {
+    pending: HashMap<String, DeviceAuthorization>,
+    lifetime: Duration,
+    interval: Duration,
+}
+
+impl DeviceCodeStore {
+    pub fn new(settings: &DeviceFlow) -> DeviceCodeStore {
+        DeviceCodeStore {
+            pending: HashMap::new(),
+            lifetime: Duration::seconds(settings.expires_in),
+            interval: Duration::seconds(settings.interval),
+        }
+    }
+
+    /// Use new settings for authorizations that are started from now on.
+    pub fn set_settings(&mut self, settings: &DeviceFlow) {
+        self.lifetime = Duration::seconds(settings.expires_in);
+        self.interval = Duration::seconds(settings.interval);
+    }
+
+    /// Start a new device authorization and return the device code for it.
+    pub fn start(&mut self, pre_grant: PreGrant) -> (String, &DeviceAuthorization) {
+        self.remove_expired();
+
+        let mut rng = rand::thread_rng();
+        let device_code: String = (&mut rng)
+            .sample_iter(&Alphanumeric)
+            .take(DEVICE_CODE_LENGTH)
+            .map(char::from)
+            .collect();
+        let user_code = loop {
+            let candidate: String = (0..USER_CODE_LENGTH)
+                .map(|_| USER_CODE_CHARSET[rng.gen_range(0..USER_CODE_CHARSET.len())] as char)
+                .collect();
+            if self.find_by_user_code(&candidate).is_none() {
+                break candidate;
+            }
+        };
+
+        let authorization = DeviceAuthorization {
+            pre_grant,
+            user_code,
+            until: Utc::now() + self.lifetime,
+            status: DeviceStatus::Pending,
+            last_poll: None,
+            interval: self.interval,
+        };
+        let authorization = self
+            .pending
+            .entry(device_code.clone())
+            .or_insert(authorization);
+        (device_code, authorization)
+    }
+
+    /// Find a pending and not yet expired authorization by the code the user entered.
+    pub fn find_by_user_code(&self, user_code: &str) -> Option<&DeviceAuthorization> {
+        let user_code = normalize_user_code(user_code);
+        let now = Utc::now();
+        self.pending.values().find(|a| {
+            a.user_code == user_code && a.until > now && matches!(a.status, DeviceStatus::Pending)
+        })
+    }
+
+    /// Record the decision of the user for a pending authorization.
+    ///
+    /// Returns `false` if there is no pending authorization for this user code.
+    pub fn decide(&mut self, user_code: &str, decision: DeviceStatus) -> bool {
+        let user_code = normalize_user_code(user_code);
+        let now = Utc::now();
+        if let Some(authorization) = self.pending.values_mut().find(|a| {
+            a.user_code == user_code && a.until > now && matches!(a.status, DeviceStatus::Pending)
+        }) {
+            authorization.status = decision;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Poll the state of the authorization for the given device code.
+    ///
+    /// Authorizations are removed once they have been approved, denied or are expired,
+    /// so each device code can only be exchanged for a token once. If the device
+    /// polls too fast, its interval is increased by 5 seconds as required by RFC 8628.
+    pub fn poll(
+        &mut self,
+        device_code: &str,
+        client_id: &str,
+    ) -> Result<ApprovedDevice, PollError> {
+        let now = Utc::now();
+        let authorization = self
+            .pending
+            .get_mut(device_code)
+            .filter(|a| a.pre_grant.client_id == client_id)
+            .ok_or(PollError::InvalidGrant)?;
+
+        if authorization.until <= now {
+            self.pending.remove(device_code);
+            return Err(PollError::ExpiredToken);
+        }
+
+        let last_poll = authorization.last_poll.replace(now);
+        match authorization.status {
+            DeviceStatus::Pending => {
+                if let Some(last_poll) = last_poll {
+                    if now - last_poll < authorization.interval {
+                        authorization.interval =
+                            authorization.interval + Duration::seconds(SLOW_DOWN_INCREMENT);
+                        return Err(PollError::SlowDown);
+                    }
+                }
+                Err(PollError::AuthorizationPending)
+            }
+            DeviceStatus::Denied => {
+                self.pending.remove(device_code);
+                Err(PollError::AccessDenied)
+            }
+            DeviceStatus::Approved { .. } => {
+                let authorization = self
+                    .pending
+                    .remove(device_code)
+                    .ok_or(PollError::InvalidGrant)?;
+                if let DeviceStatus::Approved {
+                    owner_id,
+                    extensions,
+                } = authorization.status
+                {
+                    Ok(ApprovedDevice {
+                        pre_grant: authorization.pre_grant,
+                        owner_id,
+                        extensions,
+                    })
+                } else {
+                    Err(PollError::InvalidGrant)
+                }
+            }
+        }
+    }
+
+    /// The number of pending authorizations, including expired ones that have not been removed yet.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn remove_expired(&mut self) {
+        let now = Utc::now();
+        self.pending.retain(|_, a| a.until > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pre_grant() -> PreGrant {
+        PreGrant {
+            client_id: "default".to_string(),
+            redirect_uri: "http://localhost:8080".parse::<url::Url>().unwrap().into(),
+            scope: "default-scope".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_poll_device_code() {
+        let mut store = DeviceCodeStore::new(&DeviceFlow {
+            interval: 0,
+            ..Default::default()
+        });
+        let (device_code, authorization) = store.start(pre_grant());
+        let user_code = format_user_code(&authorization.user_code).to_lowercase();
+
+        assert_eq!(
+            Err(PollError::InvalidGrant),
+            store.poll(&device_code, "other-client").map(|_| ())
+        );
+        assert_eq!(
+            Err(PollError::AuthorizationPending),
+            store.poll(&device_code, "default").map(|_| ())
+        );
+
+        assert!(store.decide(
+            &user_code,
+            DeviceStatus::Approved {
+                owner_id: "testuser".to_string(),
+                extensions: Extensions::new(),
+            }
+        ));
+        // A decision can only be made once
+        assert!(!store.decide(&user_code, DeviceStatus::Denied));
+
+        let approved = store.poll(&device_code, "default").unwrap();
+        assert_eq!("testuser", approved.owner_id);
+
+        // The device code can not be used a second time
+        assert_eq!(
+            Err(PollError::InvalidGrant),
+            store.poll(&device_code, "default").map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_slow_down() {
+        let mut store = DeviceCodeStore::new(&DeviceFlow {
+            interval: 2,
+            ..Default::default()
+        });
+        let (device_code, _) = store.start(pre_grant());
+        let poll = |store: &mut DeviceCodeStore, seconds_ago: i64| {
+            let last_poll = time::OffsetDateTime::now_utc() - time::Duration::seconds(seconds_ago);
+            store.pending.get_mut(&device_code).unwrap().last_poll =
+                Some(std::time::SystemTime::from(last_poll).into());
+            store.poll(&device_code, "default").map(|_| ())
+        };
+
+        assert_eq!(Err(PollError::AuthorizationPending), poll(&mut store, 3));
+        assert_eq!(Err(PollError::SlowDown), poll(&mut store, 1));
+        // The interval has been increased to 7 seconds
+        assert_eq!(Err(PollError::SlowDown), poll(&mut store, 3));
+        assert_eq!(Err(PollError::AuthorizationPending), poll(&mut store, 13));
+    }
+}
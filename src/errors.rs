@@ -1,3 +1,4 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
 use oxide_auth::primitives::scope::ParseScopeErr;
 use thiserror::Error;
 
@@ -9,13 +10,24 @@ pub enum StartupError {
     #[error("Scope could not be parsed because of invalid chararcter '{0}'")]
     InvalidCharacterInScope(char),
     #[error("JSON Web Token Error")]
-    JWT(#[from] jsonwebtoken::errors::Error),
+    Jwt(#[from] jsonwebtoken::errors::Error),
     #[error("Invalid configuration file")]
     Config(#[from] config::ConfigError),
     #[error("IO error")]
     IO(#[from] std::io::Error),
     #[error("TOML serialization error")]
-    TOML(#[from] toml::ser::Error),
+    Toml(#[from] toml::ser::Error),
+    #[error("The default scope {1} of client {0} is not an allowed scope")]
+    DefaultScopeNotAllowed(String, String),
+    #[error("Invalid authentication settings of client {0}: {1}")]
+    InvalidClientAuthentication(String, &'static str),
+    #[error("Could not hash client secret")]
+    Argon2(#[from] argon2::Error),
+    #[error("The registered client {0} is invalid: {1}")]
+    InvalidRegisteredClient(String, String),
+    #[cfg(not(unix))]
+    #[error("Logging to syslog is only supported on Unix")]
+    SyslogUnsupported,
 }
 
 impl From<ParseScopeErr> for StartupError {
@@ -38,13 +50,96 @@ pub enum RuntimeError {
     #[error("Could not parse template: {0}")]
     TemplateParsing(#[from] handlebars::RenderError),
     #[error("JSON Web Token error: {0}")]
-    JWT(#[from] jsonwebtoken::errors::Error),
+    Jwt(#[from] jsonwebtoken::errors::Error),
     #[error("JSON error: {0}")]
-    JSON(#[from] serde_json::error::Error),
+    Json(#[from] serde_json::error::Error),
     #[error("IO error")]
     IO(#[from] std::io::Error),
 }
 
+/// Errors of the administrative API, which are returned as JSON.
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error("The administrative API is not enabled")]
+    Disabled,
+    #[error("invalid_token")]
+    Unauthorized,
+    #[error("The server has been started without a configuration file")]
+    NoConfigFile,
+    #[error("{0}")]
+    Startup(#[from] StartupError),
+}
+
+impl ResponseError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AdminError::Disabled => StatusCode::NOT_FOUND,
+            AdminError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AdminError::NoConfigFile => StatusCode::CONFLICT,
+            AdminError::Startup(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let AdminError::Unauthorized = self {
+            response.append_header(("WWW-Authenticate", "Bearer"));
+        }
+        response.json(serde_json::json!({ "error": self.to_string() }))
+    }
+}
+
+/// Errors of the dynamic client registration as defined by RFC 7591 and RFC 7592.
+#[derive(Debug, Error)]
+pub enum RegistrationError {
+    #[error("The client registration is not enabled")]
+    Disabled,
+    /// The access token is invalid or the client does not exist
+    #[error("invalid_token")]
+    InvalidToken,
+    #[error("{0}")]
+    InvalidRedirectUri(String),
+    #[error("{0}")]
+    InvalidClientMetadata(String),
+    #[error("{0}")]
+    Startup(#[from] StartupError),
+}
+
+impl From<std::io::Error> for RegistrationError {
+    fn from(e: std::io::Error) -> Self {
+        RegistrationError::Startup(e.into())
+    }
+}
+
+impl ResponseError for RegistrationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            RegistrationError::Disabled => StatusCode::NOT_FOUND,
+            RegistrationError::InvalidToken => StatusCode::UNAUTHORIZED,
+            RegistrationError::InvalidRedirectUri(_)
+            | RegistrationError::InvalidClientMetadata(_) => StatusCode::BAD_REQUEST,
+            RegistrationError::Startup(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        let error = match self {
+            RegistrationError::InvalidToken => {
+                response.append_header(("WWW-Authenticate", "Bearer"));
+                "invalid_token"
+            }
+            RegistrationError::InvalidRedirectUri(_) => "invalid_redirect_uri",
+            RegistrationError::InvalidClientMetadata(_) => "invalid_client_metadata",
+            RegistrationError::Disabled | RegistrationError::Startup(_) => "server_error",
+        };
+        response.json(serde_json::json!({
+            "error": error,
+            "error_description": self.to_string(),
+        }))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+
+use actix_web::HttpRequest;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::debug;
+use oxide_auth::primitives::grant::Extensions;
+
+use crate::{
+    attributes::AttributeValue,
+    settings::{self, Mapping},
+};
+
+/// The user that is logged in at the proxy and their attributes.
+#[derive(Debug, Default)]
+pub struct Identity {
+    /// The resource owner, `None` if the user is not known
+    pub owner: Option<String>,
+    pub attributes: HashMap<String, AttributeValue>,
+}
+
+impl Identity {
+    /// An identity without an owner, so the authorization is denied.
+    pub fn unknown() -> Identity {
+        Identity::default()
+    }
+
+    /// Store the attributes as grant extensions, so they are available when issuing the token.
+    pub fn extensions(&self) -> Extensions {
+        let mut extensions = Extensions::new();
+        for (n, v) in &self.attributes {
+            extensions.set_raw(n.to_string(), v.to_extension());
+        }
+        extensions
+    }
+
+    /// Only keep the attributes that are configured to be included in the token.
+    fn filter_attributes(
+        mapping: &Mapping,
+        attributes: impl IntoIterator<Item = (String, AttributeValue)>,
+    ) -> HashMap<String, AttributeValue> {
+        attributes
+            .into_iter()
+            .filter_map(|(name, value)| Some((mapping.collected_header_name(&name)?, value)))
+            .collect()
+    }
+}
+
+/// A source of the identity of the user who authorizes a client.
+pub trait IdentitySource {
+    fn identify(&self, request: &HttpRequest, mapping: &Mapping) -> Identity;
+}
+
+/// Create the identity source that is configured for a client.
+pub fn identity_source(settings: &settings::IdentitySource) -> Box<dyn IdentitySource> {
+    match settings {
+        settings::IdentitySource::Headers => Box::new(HeaderSource),
+        settings::IdentitySource::Assertion {
+            header,
+            secret,
+            audience,
+            leeway,
+        } => {
+            let mut validation = Validation::new(Algorithm::HS256);
+            validation.set_audience(&[audience]);
+            validation.set_required_spec_claims(&["exp", "aud", "sub"]);
+            validation.leeway = *leeway;
+            Box::new(AssertionSource {
+                header: header.clone(),
+                key: DecodingKey::from_secret(secret.as_bytes()),
+                validation,
+            })
+        }
+        settings::IdentitySource::Static { sub, attributes } => Box::new(StaticSource {
+            sub: sub.clone(),
+            attributes: attributes.clone(),
+        }),
+    }
+}
+
+/// Takes the identity from plain HTTP headers.
+pub struct HeaderSource;
+
+impl IdentitySource for HeaderSource {
+    fn identify(&self, request: &HttpRequest, mapping: &Mapping) -> Identity {
+        let headers = request.headers();
+        // Get the resource owner from the configured `sub_header` or use the
+        // default subject if no such header is configured.
+        let owner = match &mapping.sub_header {
+            Some(sub_header) => headers
+                .get(sub_header)
+                .and_then(|remote_user| remote_user.to_str().ok())
+                .filter(|remote_user| !remote_user.is_empty())
+                .map(|remote_user| remote_user.to_string()),
+            None => Some(mapping.default_sub.clone()),
+        };
+        let attributes = headers
+            .keys()
+            .filter_map(|header| {
+                let name = mapping.collected_header_name(header.as_str())?;
+                let value = headers.get(header)?.to_str().unwrap_or_default();
+                let value = AttributeValue::parse(value, mapping.header_settings(&name));
+                Some((name, value))
+            })
+            .collect();
+        Identity { owner, attributes }
+    }
+}
+
+/// Claims of an assertion that are not used as attributes.
+const REGISTERED_CLAIMS: &[&str] = &["iss", "sub", "aud", "exp", "nbf", "iat", "jti"];
+
+/// Takes the identity from a JWS in a single header, which has to be signed,
+/// not expired and intended for this server. All claims except the
+/// registered ones are used as attributes.
+pub struct AssertionSource {
+    header: String,
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl IdentitySource for AssertionSource {
+    fn identify(&self, request: &HttpRequest, mapping: &Mapping) -> Identity {
+        let value = match request.headers().get(&self.header) {
+            Some(value) => value.to_str().unwrap_or_default(),
+            None => {
+                debug!("Missing identity assertion header {}", self.header);
+                return Identity::unknown();
+            }
+        };
+        let claims = match jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(
+            value.trim(),
+            &self.key,
+            &self.validation,
+        ) {
+            Ok(token) => token.claims,
+            Err(err) => {
+                debug!("Invalid identity assertion: {}", err);
+                return Identity::unknown();
+            }
+        };
+        let owner = claims
+            .get("sub")
+            .and_then(|sub| sub.as_str())
+            .filter(|sub| !sub.is_empty())
+            .map(|sub| sub.to_string());
+        let attributes = claims
+            .into_iter()
+            .filter(|(name, _)| !REGISTERED_CLAIMS.contains(&name.as_str()))
+            .filter_map(|(name, value)| Some((name, serde_json::from_value(value).ok()?)));
+        Identity {
+            owner,
+            attributes: Identity::filter_attributes(mapping, attributes),
+        }
+    }
+}
+
+/// Always returns the same configured identity.
+pub struct StaticSource {
+    sub: String,
+    attributes: HashMap<String, AttributeValue>,
+}
+
+impl IdentitySource for StaticSource {
+    fn identify(&self, _request: &HttpRequest, mapping: &Mapping) -> Identity {
+        Identity {
+            owner: Some(self.sub.clone()),
+            attributes: Identity::filter_attributes(mapping, self.attributes.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_identity_assertion() {
+        let mapping = Mapping {
+            include_headers: vec!["affiliation".into()],
+            ..Default::default()
+        };
+        let source = identity_source(&settings::IdentitySource::Assertion {
+            header: "X-Identity-Assertion".to_string(),
+            secret: "shared-secret".to_string(),
+            audience: "https://example.com/login".to_string(),
+            leeway: 0,
+        });
+        let assertion = |secret: &str, aud: &str, exp: i64| {
+            let claims = serde_json::json!({
+                "sub": "testuser",
+                "aud": aud,
+                "exp": exp,
+                "affiliation": ["member", "staff"],
+            });
+            let token = jsonwebtoken::encode(
+                &jsonwebtoken::Header::default(),
+                &claims,
+                &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+            )
+            .unwrap();
+            TestRequest::default()
+                .insert_header(("X-Identity-Assertion", token))
+                .to_http_request()
+        };
+        let exp = time::OffsetDateTime::now_utc().unix_timestamp() + 60;
+
+        let result = source.identify(
+            &assertion("shared-secret", "https://example.com/login", exp),
+            &mapping,
+        );
+        assert_eq!(Some("testuser".to_string()), result.owner);
+        assert_eq!(
+            AttributeValue::List(vec!["member".to_string(), "staff".to_string()]),
+            result.attributes["affiliation"]
+        );
+
+        // Wrong key, audience or expired
+        for request in [
+            assertion("other-secret", "https://example.com/login", exp),
+            assertion("shared-secret", "https://other.example.com", exp),
+            assertion("shared-secret", "https://example.com/login", exp - 120),
+            TestRequest::default().to_http_request(),
+        ] {
+            assert_eq!(None, source.identify(&request, &mapping).owner);
+        }
+    }
+}
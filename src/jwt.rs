@@ -1,20 +1,37 @@
-use std::{borrow::Cow, collections::HashMap};
+use std::{borrow::Cow, collections::HashMap, sync::Arc, time::Instant};
 
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use handlebars::handlebars_helper;
 use log::error;
 use oxide_auth::{
     endpoint::Issuer,
     primitives::{
-        grant::Grant,
+        grant::{Extensions, Grant},
         issuer::{RefreshedToken, TokenType::Bearer},
         prelude::{IssuedToken, RandomGenerator, TagGrant},
     },
 };
 
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
 use serde_json::Map;
 
-use crate::{errors::RuntimeError, settings::Settings};
+use crate::{
+    attributes::AttributeValue,
+    audit::{AuditEvent, AuditEventKind, AuditLog},
+    errors::RuntimeError,
+    metrics::{Denial, Metrics},
+    settings::{self, Settings},
+};
+
+// Outputs a variable as JSON, e.g. to include a list of values in the token
+handlebars_helper!(json_helper: |value: Json| value.to_string());
 
+/// Name of the private grant extension with the `nonce` of the authorization
+/// request, which can not be the name of a forwarded header.
+pub const NONCE_EXTENSION: &str = "oauth:nonce";
+
+#[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
@@ -22,30 +39,285 @@ pub struct Claims {
     pub exp: Option<i64>,
 }
 
+/// Create a random unique identifier for a token.
+pub fn generate_jti() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect()
+}
+
+/// A refresh token that can still be used.
+struct RefreshGrant {
+    /// The grant of the token, which is valid `until` the refresh token expires
+    grant: Grant,
+    /// All refresh tokens rotated from the same initially issued token belong to one family
+    family: String,
+    /// When the user logged in and the attributes in the grant were read
+    auth_time: DateTime<Utc>,
+}
+
+/// A refresh token that has been replaced by a new one and must not be used again.
+pub struct RotatedToken {
+    family: String,
+    until: DateTime<Utc>,
+    pub owner_id: String,
+    pub client_id: String,
+}
+
+/// An active refresh token as shown to administrators, without the token itself.
+#[derive(Debug, Serialize)]
+pub struct ActiveGrant {
+    pub sub: String,
+    pub client_id: String,
+    pub scope: String,
+    /// Identifies the refresh tokens rotated from the same initially issued token
+    pub family: String,
+    /// When the user logged in, as RFC 3339 timestamp
+    pub auth_time: String,
+    /// When the refresh token expires, as RFC 3339 timestamp
+    pub expires_at: String,
+}
+
 pub struct JWTIssuer {
     settings: Settings,
-    refresh: HashMap<String, Grant>,
+    /// The settings of all registered clients, which define the lifetimes of their tokens
+    clients: HashMap<String, settings::Client>,
+    refresh: HashMap<String, RefreshGrant>,
+    rotated: HashMap<String, RotatedToken>,
     refresh_token_generator: RandomGenerator,
+    metrics: Arc<Metrics>,
+    audit: Arc<AuditLog>,
+    /// The source IP of the request the next token is issued for
+    source_ip: Option<String>,
 }
 
 impl JWTIssuer {
-    pub fn new(settings: Settings) -> JWTIssuer {
+    pub fn new(settings: Settings, metrics: Arc<Metrics>, audit: Arc<AuditLog>) -> JWTIssuer {
         JWTIssuer {
             settings,
+            clients: HashMap::new(),
             refresh: HashMap::new(),
+            rotated: HashMap::new(),
             refresh_token_generator: RandomGenerator::new(128),
+            metrics,
+            audit,
+            source_ip: None,
         }
     }
 
-    fn create_token(
+    /// Set the source IP of the request that is handled while the issuer is
+    /// locked, which is recorded in the audit log with the issued token.
+    pub fn set_source_ip(&mut self, source_ip: Option<String>) {
+        self.source_ip = source_ip;
+    }
+
+    /// Record an issued token in the audit log.
+    fn record_issued(
+        &mut self,
+        kind: AuditEventKind,
+        grant: &Grant,
+        claims: &Map<String, serde_json::Value>,
+    ) {
+        let claim = |name: &str| {
+            claims
+                .get(name)
+                .and_then(|value| value.as_str())
+                .map(|value| value.to_string())
+        };
+        self.audit.record(&AuditEvent {
+            client_id: Some(grant.client_id.clone()),
+            sub: claim("sub"),
+            source_ip: self.source_ip.take(),
+            jti: claim("jti"),
+            ..AuditEvent::new(kind, "issued")
+        });
+    }
+
+    /// Use new settings for tokens that are issued from now on.
+    pub fn set_settings(&mut self, settings: Settings) {
+        self.settings = settings;
+    }
+
+    /// Issue tokens for a client with its lifetimes, or replace the settings
+    /// of a client with the same ID.
+    pub fn register_client(&mut self, client: &settings::Client) {
+        self.clients.insert(client.id.clone(), client.clone());
+    }
+
+    /// Remove a client and revoke all its refresh tokens.
+    ///
+    /// Returns the number of revoked tokens.
+    pub fn unregister_client(&mut self, client_id: &str) -> usize {
+        self.clients.remove(client_id);
+        self.revoke_client(client_id)
+    }
+
+    /// The settings of the client a grant belongs to, or of the configured
+    /// client if it is not registered.
+    fn client(&self, client_id: &str) -> &settings::Client {
+        self.clients.get(client_id).unwrap_or(&self.settings.client)
+    }
+
+    /// Revoke all refresh tokens of a user, either for a single client or for all clients.
+    ///
+    /// Returns the number of revoked tokens.
+    pub fn revoke(&mut self, owner_id: &str, client_id: Option<&str>) -> usize {
+        let revoked = |owner: &str, client: &str| {
+            owner == owner_id && client_id.map_or(true, |c| client == c)
+        };
+        let before = self.refresh.len();
+        self.refresh
+            .retain(|_, refresh| !revoked(&refresh.grant.owner_id, &refresh.grant.client_id));
+        self.rotated
+            .retain(|_, rotated| !revoked(&rotated.owner_id, &rotated.client_id));
+        before - self.refresh.len()
+    }
+
+    /// Revoke all refresh tokens of a client, e.g. when it is removed.
+    ///
+    /// Returns the number of revoked tokens.
+    pub fn revoke_client(&mut self, client_id: &str) -> usize {
+        let before = self.refresh.len();
+        self.refresh
+            .retain(|_, refresh| refresh.grant.client_id != client_id);
+        self.rotated
+            .retain(|_, rotated| rotated.client_id != client_id);
+        before - self.refresh.len()
+    }
+
+    /// Check if a refresh token has already been rotated, which means it has
+    /// been leaked, and revoke its whole family in that case.
+    ///
+    /// Returns the rotated token, so the reuse can be logged.
+    pub fn detect_reuse(&mut self, refresh: &str) -> Option<RotatedToken> {
+        let rotated = self.rotated.remove(refresh)?;
+        self.refresh
+            .retain(|_, refresh| refresh.family != rotated.family);
+        self.rotated
+            .retain(|_, other| other.family != rotated.family);
+        self.metrics.record_denial(Denial::RefreshTokenReuse);
+        Some(rotated)
+    }
+
+    /// Store a new refresh token for the grant, which expires after the
+    /// lifetime configured for its client, and remove all expired tokens.
+    ///
+    /// The lifetime is limited by the maximum age of the attributes, which
+    /// have been read when the user logged in, and by the lifetime of the
+    /// whole refresh chain.
+    fn store_refresh_token(
+        &mut self,
+        grant: &Grant,
+        family: String,
+        auth_time: DateTime<Utc>,
+    ) -> Result<String, ()> {
+        let now = Utc::now();
+        self.refresh.retain(|_, refresh| refresh.grant.until > now);
+        self.rotated.retain(|_, rotated| rotated.until > now);
+
+        let client = self.client(&grant.client_id);
+        let mut until = now + Duration::seconds(client.refresh_token_lifetime);
+        for limit in [client.max_attribute_age, client.refresh_chain_lifetime]
+            .iter()
+            .flatten()
+        {
+            until = until.min(auth_time + Duration::seconds(*limit));
+        }
+        let refresh = self.refresh_token_generator.tag(0, grant)?;
+        self.refresh.insert(
+            refresh.clone(),
+            RefreshGrant {
+                grant: Grant {
+                    until,
+                    ..grant.clone()
+                },
+                family,
+                auth_time,
+            },
+        );
+        Ok(refresh)
+    }
+
+    /// Let the grant expire after the lifetime of access tokens configured
+    /// for its client, regardless of how it has been granted.
+    fn with_token_lifetime(&self, grant: Grant) -> Grant {
+        let lifetime = self.client(&grant.client_id).access_token_lifetime;
+        Grant {
+            until: Utc::now() + Duration::seconds(lifetime),
+            ..grant
+        }
+    }
+
+    /// All refresh tokens that have not expired, ordered by user, client and login time.
+    pub fn active_grants(&self) -> Vec<ActiveGrant> {
+        let now = Utc::now();
+        let mut grants: Vec<_> = self
+            .refresh
+            .values()
+            .filter(|refresh| refresh.grant.until > now)
+            .collect();
+        grants.sort_by(|a, b| {
+            (&a.grant.owner_id, &a.grant.client_id, a.auth_time).cmp(&(
+                &b.grant.owner_id,
+                &b.grant.client_id,
+                b.auth_time,
+            ))
+        });
+        grants
+            .into_iter()
+            .map(|refresh| ActiveGrant {
+                sub: refresh.grant.owner_id.clone(),
+                client_id: refresh.grant.client_id.clone(),
+                scope: refresh.grant.scope.to_string(),
+                family: refresh.family.clone(),
+                auth_time: refresh.auth_time.to_rfc3339_opts(SecondsFormat::Secs, true),
+                expires_at: refresh
+                    .grant
+                    .until
+                    .to_rfc3339_opts(SecondsFormat::Secs, true),
+            })
+            .collect()
+    }
+
+    /// The number of stored refresh tokens.
+    pub fn refresh_tokens(&self) -> usize {
+        self.refresh.len()
+    }
+
+    /// Check that a token can be created for the configured client, i.e. that
+    /// the template renders to valid JSON and can be signed.
+    pub fn check_template(&self) -> Result<(), RuntimeError> {
+        let client = &self.settings.client;
+        let grant = Grant {
+            owner_id: "readiness-check".to_string(),
+            client_id: client.id.clone(),
+            scope: client
+                .default_scope()
+                .unwrap_or_else(|_| "default-scope".parse().unwrap()),
+            redirect_uri: client
+                .redirect_uri
+                .parse()
+                .unwrap_or_else(|_| "http://localhost".parse().unwrap()),
+            until: chrono::Utc::now() + chrono::Duration::minutes(1),
+            extensions: Extensions::new(),
+        };
+        self.sign(&self.create_claims(&grant, false)?)?;
+        Ok(())
+    }
+
+    fn create_claims(
         &self,
         grant: &oxide_auth::primitives::grant::Grant,
-    ) -> Result<String, RuntimeError> {
+        with_nonce: bool,
+    ) -> Result<Map<String, serde_json::Value>, RuntimeError> {
         let sub = grant.owner_id.clone();
         let exp = grant.until.timestamp();
 
         // Parse template and apply substitutions
-        let hb = handlebars::Handlebars::new();
+        let mut hb = handlebars::Handlebars::new();
+        hb.register_helper("json", Box::new(json_helper));
         let token_template: Cow<str> =
             if let Some(token_template_file) = &self.settings.mapping.token_template {
                 std::fs::read_to_string(token_template_file)?.into()
@@ -53,22 +325,91 @@ impl JWTIssuer {
                 include_str!("default-token-template.json").into()
             };
 
-        let mut variables: HashMap<String, String> = HashMap::new();
-        variables.insert("sub".to_string(), sub);
-        variables.insert("exp".to_string(), exp.to_string());
-        // Add all public extensions as arguments
+        let scope = grant.scope.to_string();
+        let scopes: Map<String, serde_json::Value> = grant
+            .scope
+            .iter()
+            .map(|s| (s.to_string(), true.into()))
+            .collect();
+
+        let mut variables: Map<String, serde_json::Value> = Map::new();
+        variables.insert("sub".to_string(), sub.into());
+        variables.insert("exp".to_string(), exp.into());
+        variables.insert("scope".to_string(), scope.clone().into());
+        variables.insert("scopes".to_string(), scopes.into());
+        // Add all public extensions as arguments, unless they are restricted to a scope that has not been granted
+        let mut attributes = Vec::new();
         for (k, v) in grant.extensions.public() {
-            variables
-                .entry(k.to_string())
-                .or_insert(v.unwrap_or_default().to_string());
+            if self.settings.mapping.is_header_visible(k, &grant.scope) {
+                let attribute = AttributeValue::from_extension(v.unwrap_or_default());
+                let value: serde_json::Value = attribute.clone().into();
+                for name in self.settings.mapping.variable_names(k) {
+                    variables.entry(name).or_insert_with(|| value.clone());
+                }
+                attributes.push((k, attribute));
+            }
+        }
+        // The nonce is only bound to the token issued for the authorization
+        // request, not to the tokens issued when refreshing it
+        let nonce = grant
+            .extensions
+            .private()
+            .find(|(name, _)| *name == NONCE_EXTENSION)
+            .and_then(|(_, nonce)| nonce)
+            .filter(|_| with_nonce);
+        if let Some(nonce) = nonce {
+            variables.insert("nonce".to_string(), nonce.into());
         }
 
-        let unsigned_token_raw = hb.render_template(&token_template, &variables)?;
+        let unsigned_token_raw = hb
+            .render_template(&token_template, &variables)
+            .inspect_err(|_| {
+                self.metrics.record_template_error();
+            })?;
 
         // Parse JSON so encoding it with serde later on will produce a correct value
-        let unsigned_token: Map<String, serde_json::Value> =
-            serde_json::from_str(&unsigned_token_raw)?;
+        let mut unsigned_token: Map<String, serde_json::Value> =
+            serde_json::from_str(&unsigned_token_raw).inspect_err(|_| {
+                self.metrics.record_template_error();
+            })?;
 
+        // Add the claims of the attribute profile, the client, the granted
+        // scope, the nonce and the claims configured for the scope, if not
+        // already set by the template
+        if let Some(profile) = &self.settings.mapping.profile {
+            for (claim, value) in profile.create_claims(&attributes) {
+                unsigned_token.entry(claim).or_insert(value);
+            }
+        }
+        unsigned_token
+            .entry("client_id")
+            .or_insert_with(|| grant.client_id.clone().into());
+        unsigned_token
+            .entry("scope")
+            .or_insert_with(|| scope.into());
+        if let Some(nonce) = nonce {
+            unsigned_token
+                .entry("nonce")
+                .or_insert_with(|| nonce.into());
+        }
+        unsigned_token
+            .entry("jti")
+            .or_insert_with(|| generate_jti().into());
+        for granted in grant.scope.iter() {
+            if let Some(scope_mapping) = self.settings.mapping.scopes.get(granted) {
+                for (claim, value) in &scope_mapping.claims {
+                    unsigned_token
+                        .entry(claim.to_string())
+                        .or_insert_with(|| value.clone());
+                }
+            }
+        }
+
+        Ok(unsigned_token)
+    }
+
+    /// Sign the given claims with the configured key.
+    pub fn sign(&self, claims: &Map<String, serde_json::Value>) -> Result<String, RuntimeError> {
         let key = self
             .settings
             .client
@@ -76,7 +417,9 @@ impl JWTIssuer {
             .create_encoding_key()?;
         let header =
             jsonwebtoken::Header::new(self.settings.client.token_verification.as_algorithm());
-        let token_str = jsonwebtoken::encode(&header, &unsigned_token, &key)?;
+        let started = Instant::now();
+        let token_str = jsonwebtoken::encode(&header, claims, &key)?;
+        self.metrics.record_signing(started.elapsed());
 
         Ok(token_str)
     }
@@ -87,12 +430,13 @@ impl Issuer for JWTIssuer {
         &mut self,
         grant: oxide_auth::primitives::grant::Grant,
     ) -> Result<oxide_auth::primitives::prelude::IssuedToken, ()> {
-        let token = self
-            .create_token(&grant)
-            .map_err(|e| (error!("Could not issue token: {}", e)))?;
-        let refresh = self.refresh_token_generator.tag(0, &grant)?;
-
-        self.refresh.insert(refresh.clone(), grant.clone());
+        let grant = self.with_token_lifetime(grant);
+        let claims = self
+            .create_claims(&grant, true)
+            .and_then(|claims| Ok((self.sign(&claims)?, claims)));
+        let (token, claims) = claims.map_err(|e| error!("Could not issue token: {}", e))?;
+        let refresh = self.store_refresh_token(&grant, generate_jti(), Utc::now())?;
+        self.record_issued(AuditEventKind::Token, &grant, &claims);
 
         Ok(IssuedToken {
             token,
@@ -107,15 +451,30 @@ impl Issuer for JWTIssuer {
         refresh: &str,
         grant: oxide_auth::primitives::grant::Grant,
     ) -> Result<oxide_auth::primitives::issuer::RefreshedToken, ()> {
-        // Invalidate old refresh token
-        self.refresh.remove(refresh);
+        let grant = self.with_token_lifetime(grant);
+        let claims = self
+            .create_claims(&grant, false)
+            .and_then(|claims| Ok((self.sign(&claims)?, claims)));
+        let (token, claims) = claims.map_err(|e| error!("Could not refresh token: {}", e))?;
 
-        let token = self
-            .create_token(&grant)
-            .map_err(|e| (error!("Could not refresh token: {}", e)))?;
-        let new_refresh = self.refresh_token_generator.tag(0, &grant)?;
+        // Invalidate old refresh token, but remember it to detect if it is used again
+        let (family, until, auth_time) = match self.refresh.remove(refresh) {
+            Some(old) => (old.family, old.grant.until, old.auth_time),
+            None => (generate_jti(), grant.until, Utc::now()),
+        };
+        self.rotated.insert(
+            refresh.to_string(),
+            RotatedToken {
+                family: family.clone(),
+                until,
+                owner_id: grant.owner_id.clone(),
+                client_id: grant.client_id.clone(),
+            },
+        );
+        let new_refresh = self.store_refresh_token(&grant, family, auth_time)?;
+        self.record_issued(AuditEventKind::Refresh, &grant, &claims);
         Ok(RefreshedToken {
-            token: token,
+            token,
             refresh: Some(new_refresh),
             until: grant.until,
             token_type: Bearer,
@@ -133,6 +492,13 @@ impl Issuer for JWTIssuer {
         &'a self,
         token: &'a str,
     ) -> Result<Option<oxide_auth::primitives::grant::Grant>, ()> {
-        Ok(self.refresh.get(token).map(|grant| grant.clone()))
+        // The returned grant is only valid until the refresh token expires,
+        // a new grant for the access token is created when refreshing.
+        let now = Utc::now();
+        Ok(self
+            .refresh
+            .get(token)
+            .filter(|refresh| refresh.grant.until > now)
+            .map(|refresh| refresh.grant.clone()))
     }
 }
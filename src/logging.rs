@@ -0,0 +1,342 @@
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use chrono::{SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::{
+    errors::StartupError,
+    settings::{LogFormat, LogOutput, Logging},
+};
+
+/// Facility `user` as defined in RFC 5424.
+#[cfg(unix)]
+const SYSLOG_FACILITY: u8 = 1;
+
+/// A log file that is renamed to `<path>.1` once it exceeds its maximum size,
+/// shifting older files to `<path>.2` and so on.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+enum Output {
+    Terminal,
+    File(RotatingFile),
+    #[cfg(unix)]
+    Syslog(UnixDatagram),
+}
+
+struct Logger {
+    level: LevelFilter,
+    /// Levels of specific modules, the longest module names first
+    modules: Vec<(String, LevelFilter)>,
+    format: LogFormat,
+    output: Mutex<Output>,
+}
+
+impl Logger {
+    fn new(settings: &Logging) -> Result<Logger, StartupError> {
+        let mut modules: Vec<(String, LevelFilter)> = settings
+            .module_levels()
+            .into_iter()
+            .map(|(module, level)| (module, level.into()))
+            .collect();
+        modules.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+
+        let output = match &settings.output {
+            LogOutput::Terminal => Output::Terminal,
+            LogOutput::File {
+                path,
+                max_size,
+                max_files,
+            } => Output::File(RotatingFile::open(path.into(), *max_size, *max_files)?),
+            #[cfg(unix)]
+            LogOutput::Syslog { socket } => {
+                let datagram = UnixDatagram::unbound()?;
+                datagram.connect(socket)?;
+                Output::Syslog(datagram)
+            }
+            #[cfg(not(unix))]
+            LogOutput::Syslog { .. } => return Err(StartupError::SyslogUnsupported),
+        };
+
+        Ok(Logger {
+            level: settings.default_level().into(),
+            modules,
+            format: settings.format,
+            output: Mutex::new(output),
+        })
+    }
+
+    /// The level for a log target, which is the module path of the message.
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target == module
+                    || target
+                        .strip_prefix(module.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.level, std::cmp::max)
+    }
+
+    fn format(&self, record: &Record, with_timestamp: bool) -> String {
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        match self.format {
+            LogFormat::Text if with_timestamp => format!(
+                "{} {:<5} [{}] {}",
+                timestamp,
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+            LogFormat::Text => format!("[{}] {}", record.target(), record.args()),
+            LogFormat::Json => {
+                let mut line = serde_json::json!({
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                });
+                if with_timestamp {
+                    line["timestamp"] = timestamp.into();
+                }
+                line.to_string()
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut output = self.output.lock().unwrap();
+        // Errors while logging can not be logged, so they are ignored
+        let _ = match &mut *output {
+            Output::Terminal => {
+                let line = self.format(record, true);
+                if record.level() == Level::Error {
+                    writeln!(io::stderr(), "{}", line)
+                } else {
+                    writeln!(io::stdout(), "{}", line)
+                }
+            }
+            Output::File(file) => file.write_line(&self.format(record, true)),
+            #[cfg(unix)]
+            Output::Syslog(socket) => {
+                let severity = match record.level() {
+                    Level::Error => 3,
+                    Level::Warn => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7,
+                };
+                // The syslog daemon adds the timestamp itself
+                let message = format!(
+                    "<{}>{}[{}]: {}",
+                    SYSLOG_FACILITY * 8 + severity,
+                    env!("CARGO_PKG_NAME"),
+                    std::process::id(),
+                    self.format(record, false)
+                );
+                socket.send(message.as_bytes()).map(|_| ())
+            }
+        };
+    }
+
+    fn flush(&self) {
+        let mut output = self.output.lock().unwrap();
+        let _ = match &mut *output {
+            Output::Terminal => io::stdout().flush(),
+            Output::File(file) => file.file.flush(),
+            #[cfg(unix)]
+            Output::Syslog(_) => Ok(()),
+        };
+    }
+}
+
+/// Initialize the global logger with the configured level, filters, format and output.
+pub fn init(settings: &Logging) -> Result<(), StartupError> {
+    let logger = Logger::new(settings)?;
+    let max_level = logger.max_level();
+    // There can only be one global logger, e.g. when running the tests it
+    // might have been initialized already
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(max_level);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::LogLevel;
+
+    #[test]
+    fn test_module_levels() {
+        let mut settings = Logging::default();
+        settings
+            .modules
+            .insert("forwarding_oauth2_server::api".to_string(), LogLevel::Debug);
+        let logger = Logger::new(&settings).unwrap();
+
+        assert_eq!(
+            LevelFilter::Info,
+            logger.level_for("forwarding_oauth2_server")
+        );
+        assert_eq!(
+            LevelFilter::Debug,
+            logger.level_for("forwarding_oauth2_server::api")
+        );
+        assert_eq!(
+            LevelFilter::Info,
+            logger.level_for("forwarding_oauth2_server::api_extra")
+        );
+        assert_eq!(LevelFilter::Warn, logger.level_for("actix_web::middleware"));
+        assert_eq!(LevelFilter::Debug, logger.max_level());
+
+        settings.debug = true;
+        let logger = Logger::new(&settings).unwrap();
+        assert_eq!(
+            LevelFilter::Debug,
+            logger.level_for("actix_web::middleware")
+        );
+    }
+
+    #[test]
+    fn test_rotate_log_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["first", "second", "third", "fourth"] {
+            file.write_line(line).unwrap();
+        }
+
+        assert_eq!("fourth\n", fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            "third\n",
+            fs::read_to_string(dir.path().join("server.log.1")).unwrap()
+        );
+        assert_eq!(
+            "second\n",
+            fs::read_to_string(dir.path().join("server.log.2")).unwrap()
+        );
+        assert!(!dir.path().join("server.log.3").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_syslog_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("log");
+        let server = UnixDatagram::bind(&socket).unwrap();
+        let settings = Logging {
+            output: LogOutput::Syslog {
+                socket: socket.to_string_lossy().to_string(),
+            },
+            ..Logging::default()
+        };
+        let logger = Logger::new(&settings).unwrap();
+        logger.log(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("forwarding_oauth2_server::api")
+                .args(format_args!("Invalid token"))
+                .build(),
+        );
+
+        let mut buffer = [0; 256];
+        let len = server.recv(&mut buffer).unwrap();
+        let message = std::str::from_utf8(&buffer[..len]).unwrap();
+        assert!(message.starts_with("<12>forwarding-oauth2-server["));
+        assert!(message.ends_with("]: [forwarding_oauth2_server::api] Invalid token"));
+    }
+
+    #[cfg(not(unix))]
+    #[test]
+    fn test_syslog_unsupported() {
+        let settings = Logging {
+            output: LogOutput::Syslog {
+                socket: "/dev/log".to_string(),
+            },
+            ..Logging::default()
+        };
+        assert!(matches!(
+            Logger::new(&settings),
+            Err(StartupError::SyslogUnsupported)
+        ));
+    }
+}
@@ -1,6 +1,25 @@
+// `Option::is_none_or`, which clippy suggests instead, requires a newer toolchain
+#![allow(clippy::unnecessary_map_or)]
+
+mod admin;
 mod api;
+mod attributes;
+mod audit;
+mod auth_codes;
+mod client_auth;
+mod client_store;
+mod consent;
+mod device;
 mod errors;
+mod identity;
 mod jwt;
+mod logging;
+mod metrics;
+mod pages;
+mod profiles;
+mod pushed_requests;
+mod registrar;
+mod registration;
 mod settings;
 mod state;
 
@@ -12,40 +31,17 @@ use actix_web::{
 };
 use clap::{Arg, ArgSettings};
 use errors::StartupError;
-use log::{warn, LevelFilter};
-use simplelog::{ColorChoice, Config, SimpleLogger, TermLogger, TerminalMode};
+use log::warn;
 
 use crate::{settings::Settings, state::State};
 
 fn init_app(settings: &Settings) -> std::result::Result<State, StartupError> {
-    let log_filter = if settings.logging.debug {
-        LevelFilter::Debug
-    } else {
-        LevelFilter::Info
-    };
-
-    let mut log_config = simplelog::ConfigBuilder::new();
+    logging::init(&settings.logging)?;
     if settings.logging.debug {
-        warn!("Enabling request logging to console in debug mode");
-    } else {
-        log_config.add_filter_ignore_str("actix_web:");
+        warn!("Enabling request logging in debug mode");
     }
 
-    let log_config = log_config.build();
-
-    if let Err(e) = TermLogger::init(
-        LevelFilter::Info,
-        Config::default(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    ) {
-        println!("Error, can't initialize the terminal log output: {}.\nWill degrade to a more simple logger", e);
-        if let Err(e_simple) = SimpleLogger::init(log_filter, log_config) {
-            println!("Simple logging failed too: {}", e_simple);
-        }
-    }
-
-    let state = State::new(&settings)?;
+    let state = State::new(settings)?;
     Ok(state)
 }
 
@@ -90,9 +86,43 @@ pub async fn main() -> std::io::Result<()> {
 
     let state = web::Data::new(state);
 
+    if let (true, Some(port)) = (settings.metrics.enabled, settings.metrics.port) {
+        let metrics_state = state.clone();
+        let metrics_server = HttpServer::new(move || {
+            App::new()
+                .app_data(metrics_state.clone())
+                .route("/metrics", web::get().to(api::metrics))
+        })
+        .workers(1)
+        .bind(format!("{}:{}", settings.bind.host, port))?
+        .run();
+        actix_web::rt::spawn(metrics_server);
+    }
+    let metrics_on_main_port = settings.metrics.enabled && settings.metrics.port.is_none();
+    if let (true, Some(port)) = (settings.admin.enabled, settings.admin.port) {
+        let admin_state = state.clone();
+        let admin_server = HttpServer::new(move || {
+            App::new()
+                .app_data(admin_state.clone())
+                .wrap(NormalizePath::new(TrailingSlash::Trim))
+                .configure(admin::configure)
+        })
+        .workers(1)
+        .bind(format!(
+            "{}:{}",
+            settings.admin.host.as_ref().unwrap_or(&settings.bind.host),
+            port
+        ))?
+        .run();
+        actix_web::rt::spawn(admin_server);
+    }
+    let admin_on_main_port = settings.admin.enabled && settings.admin.port.is_none();
+    let registration_enabled = settings.registration.enabled;
+
     let server = HttpServer::new(move || {
-        App::new()
+        let app = App::new()
             .app_data(state.clone())
+            .wrap_fn(api::record_request)
             .wrap(NormalizePath::new(TrailingSlash::Trim))
             .wrap(Logger::default())
             .service(
@@ -100,9 +130,42 @@ pub async fn main() -> std::io::Result<()> {
                     .route(web::get().to(api::authorize))
                     .route(web::post().to(api::authorize)),
             )
+            .route("/par", web::post().to(api::pushed_authorization_request))
+            .route(
+                "/device_authorization",
+                web::post().to(api::device_authorization),
+            )
+            .service(
+                web::resource("/device")
+                    .route(web::get().to(api::device))
+                    .route(web::post().to(api::device_decision)),
+            )
             .route("/token", web::post().to(api::token))
             .route("/refresh", web::post().to(api::refresh))
             .route("/userinfo", web::get().to(api::userinfo))
+            .service(
+                web::resource("/logout")
+                    .route(web::get().to(api::logout))
+                    .route(web::post().to(api::logout)),
+            )
+            .route("/health", web::get().to(api::health))
+            .route("/ready", web::get().to(api::ready))
+            .route("/version", web::get().to(api::version));
+        let app = if registration_enabled {
+            app.configure(registration::configure)
+        } else {
+            app
+        };
+        let app = if admin_on_main_port {
+            app.configure(admin::configure)
+        } else {
+            app
+        };
+        if metrics_on_main_port {
+            app.route("/metrics", web::get().to(api::metrics))
+        } else {
+            app
+        }
     })
     .bind(format!("{}:{}", settings.bind.host, settings.bind.port))
     .expect("Failed to bind to socket");
@@ -0,0 +1,256 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+const PREFIX: &str = "forwarding_oauth2";
+
+/// Upper bounds of the buckets for the signing latency in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// Reasons why an authorization or token request was denied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Denial {
+    /// The user could not be determined, e.g. because the `sub_header` is missing
+    MissingIdentity,
+    InvalidRedirect,
+    InvalidClientCredentials,
+    /// A refresh token was used again after it had been rotated
+    RefreshTokenReuse,
+    /// A request object was not signed by the client or not intended for this server
+    InvalidRequestObject,
+}
+
+impl Denial {
+    fn label(&self) -> &'static str {
+        match self {
+            Denial::MissingIdentity => "missing_identity",
+            Denial::InvalidRedirect => "invalid_redirect",
+            Denial::InvalidClientCredentials => "invalid_client_credentials",
+            Denial::RefreshTokenReuse => "refresh_token_reuse",
+            Denial::InvalidRequestObject => "invalid_request_object",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// The current sizes of the stores, which are collected when the metrics are rendered.
+pub struct StoreSizes {
+    pub refresh_tokens: usize,
+    pub authorization_codes: usize,
+    pub device_codes: usize,
+    pub pushed_requests: usize,
+}
+
+/// Metrics about the requests and issued tokens in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    denials: Mutex<BTreeMap<Denial, u64>>,
+    template_errors: AtomicU64,
+    signing: Mutex<Histogram>,
+}
+
+impl Metrics {
+    pub fn record_request(&self, endpoint: &str, status: u16) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((endpoint.to_string(), status))
+            .or_default() += 1;
+    }
+
+    pub fn record_denial(&self, denial: Denial) {
+        *self.denials.lock().unwrap().entry(denial).or_default() += 1;
+    }
+
+    pub fn record_template_error(&self) {
+        self.template_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_signing(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut histogram = self.signing.lock().unwrap();
+        for (bucket, upper_bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= upper_bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self, sizes: &StoreSizes) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "requests_total",
+            "counter",
+            "Handled requests by endpoint and status code",
+        );
+        for ((endpoint, status), count) in self.requests.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "{}_requests_total{{endpoint=\"{}\",status=\"{}\"}} {}",
+                PREFIX,
+                escape_label(endpoint),
+                status,
+                count
+            )
+            .ok();
+        }
+
+        header(
+            &mut out,
+            "denials_total",
+            "counter",
+            "Denied requests by reason",
+        );
+        for (denial, count) in self.denials.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "{}_denials_total{{reason=\"{}\"}} {}",
+                PREFIX,
+                denial.label(),
+                count
+            )
+            .ok();
+        }
+
+        header(
+            &mut out,
+            "template_errors_total",
+            "counter",
+            "Token templates that could not be rendered",
+        );
+        writeln!(
+            out,
+            "{}_template_errors_total {}",
+            PREFIX,
+            self.template_errors.load(Ordering::Relaxed)
+        )
+        .ok();
+
+        for (name, help, value) in [
+            (
+                "refresh_tokens",
+                "Stored refresh tokens",
+                sizes.refresh_tokens,
+            ),
+            (
+                "authorization_codes",
+                "Stored authorization codes",
+                sizes.authorization_codes,
+            ),
+            (
+                "device_codes",
+                "Pending device authorizations",
+                sizes.device_codes,
+            ),
+            (
+                "pushed_requests",
+                "Stored pushed authorization requests",
+                sizes.pushed_requests,
+            ),
+        ] {
+            header(&mut out, name, "gauge", help);
+            writeln!(out, "{}_{} {}", PREFIX, name, value).ok();
+        }
+
+        header(
+            &mut out,
+            "signing_duration_seconds",
+            "histogram",
+            "Time needed to sign a token",
+        );
+        let histogram = self.signing.lock().unwrap();
+        for (count, upper_bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+            writeln!(
+                out,
+                "{}_signing_duration_seconds_bucket{{le=\"{}\"}} {}",
+                PREFIX, upper_bound, count
+            )
+            .ok();
+        }
+        writeln!(
+            out,
+            "{}_signing_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            PREFIX, histogram.count
+        )
+        .ok();
+        writeln!(
+            out,
+            "{}_signing_duration_seconds_sum {}",
+            PREFIX, histogram.sum
+        )
+        .ok();
+        writeln!(
+            out,
+            "{}_signing_duration_seconds_count {}",
+            PREFIX, histogram.count
+        )
+        .ok();
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    writeln!(out, "# HELP {}_{} {}", PREFIX, name, help).ok();
+    writeln!(out, "# TYPE {}_{} {}", PREFIX, name, metric_type).ok();
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let metrics = Metrics::default();
+        metrics.record_request("/token", 200);
+        metrics.record_request("/token", 200);
+        metrics.record_denial(Denial::InvalidRedirect);
+        metrics.record_signing(Duration::from_millis(2));
+
+        let rendered = metrics.render(&StoreSizes {
+            refresh_tokens: 3,
+            authorization_codes: 1,
+            device_codes: 0,
+            pushed_requests: 0,
+        });
+        assert!(rendered
+            .contains("forwarding_oauth2_requests_total{endpoint=\"/token\",status=\"200\"} 2\n"));
+        assert!(
+            rendered.contains("forwarding_oauth2_denials_total{reason=\"invalid_redirect\"} 1\n")
+        );
+        assert!(rendered.contains("forwarding_oauth2_refresh_tokens 3\n"));
+        assert!(rendered
+            .contains("forwarding_oauth2_signing_duration_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(rendered
+            .contains("forwarding_oauth2_signing_duration_seconds_bucket{le=\"0.0025\"} 1\n"));
+        assert!(rendered.contains("forwarding_oauth2_signing_duration_seconds_count 1\n"));
+    }
+}
@@ -0,0 +1,211 @@
+use std::path::{Path, PathBuf};
+
+use actix_web::{http::StatusCode, HttpRequest, HttpResponse, HttpResponseBuilder};
+use log::error;
+
+use crate::settings;
+
+/// Pages that are shown to the user in the browser if the login fails.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Page {
+    /// The user could not be identified or is not allowed to log in
+    Denied,
+    /// The client or its redirect URI is unknown, so the user can not be redirected back
+    InvalidClient,
+    /// An internal error occurred
+    Error,
+    /// The user has been logged out and there is no page to redirect to
+    LoggedOut,
+}
+
+impl Page {
+    fn name(&self) -> &'static str {
+        match self {
+            Page::Denied => "denied",
+            Page::InvalidClient => "invalid_client",
+            Page::Error => "error",
+            Page::LoggedOut => "logged_out",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Page::Denied => StatusCode::FORBIDDEN,
+            Page::InvalidClient => StatusCode::BAD_REQUEST,
+            Page::Error => StatusCode::INTERNAL_SERVER_ERROR,
+            Page::LoggedOut => StatusCode::OK,
+        }
+    }
+
+    /// The default title and message, which are also available in custom templates.
+    fn default_text(&self) -> (&'static str, &'static str) {
+        match self {
+            Page::Denied => (
+                "Access denied",
+                "Your account could not be determined or is not allowed to log in to this application.",
+            ),
+            Page::InvalidClient => (
+                "Invalid login request",
+                "The application that sent you here is unknown or used an invalid address to return to.",
+            ),
+            Page::Error => (
+                "Internal error",
+                "The login failed because of an internal error. Please try again later.",
+            ),
+            Page::LoggedOut => (
+                "Logged out",
+                "You have been logged out. You can close this page now.",
+            ),
+        }
+    }
+}
+
+/// Checks that a language tag only consists of letters and digits separated
+/// by `-`, so it can be used in the file name of a template.
+fn is_language_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.split('-').all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        })
+}
+
+/// Get the language tags from an `Accept-Language` header, ordered by their
+/// quality and including the primary language of each tag, e.g. `de` for `de-AT`.
+pub fn accepted_languages(accept_language: &str) -> Vec<String> {
+    let mut languages: Vec<(String, f32)> = accept_language
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let tag = parts.next()?.trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if !is_language_tag(&tag) || quality <= 0.0 {
+                None
+            } else {
+                Some((tag, quality))
+            }
+        })
+        .collect();
+    // Sorting is stable, so tags with the same quality keep their order
+    languages.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut result = Vec::new();
+    for (tag, _) in languages {
+        let primary = tag.split('-').next().unwrap_or_default().to_string();
+        for candidate in [tag, primary] {
+            if !result.contains(&candidate) {
+                result.push(candidate);
+            }
+        }
+    }
+    result
+}
+
+/// Renders the configured error pages, falling back to built-in pages.
+pub struct Pages {
+    directory: Option<PathBuf>,
+}
+
+impl Pages {
+    pub fn new(settings: &settings::Pages) -> Pages {
+        Pages {
+            directory: settings.directory.as_ref().map(PathBuf::from),
+        }
+    }
+
+    /// Find a template in the preferred language of the user.
+    ///
+    /// For a template `denied`, the templates are looked up as `denied.<language>.html`
+    /// and `denied.html` in the configured directory.
+    fn find_template(&self, name: &str, languages: &[String]) -> Option<(String, Option<String>)> {
+        let directory: &Path = self.directory.as_deref()?;
+        for language in languages {
+            let path = directory.join(format!("{}.{}.html", name, language));
+            if let Ok(template) = std::fs::read_to_string(path) {
+                return Some((template, Some(language.clone())));
+            }
+        }
+        let path = directory.join(format!("{}.html", name));
+        std::fs::read_to_string(path)
+            .ok()
+            .map(|template| (template, None))
+    }
+
+    /// Render the custom template with the given name in the language of the
+    /// user or the built-in template, if there is no custom one.
+    ///
+    /// The language of the template is available as `lang` variable.
+    pub fn render_html(
+        &self,
+        name: &str,
+        default_template: &str,
+        http_req: &HttpRequest,
+        mut variables: serde_json::Value,
+    ) -> Result<String, handlebars::RenderError> {
+        let languages = http_req
+            .headers()
+            .get("Accept-Language")
+            .and_then(|header| header.to_str().ok())
+            .map(accepted_languages)
+            .unwrap_or_default();
+        let (template, lang) = self
+            .find_template(name, &languages)
+            .unwrap_or_else(|| (default_template.to_string(), None));
+        variables["lang"] = lang.into();
+        handlebars::Handlebars::new().render_template(&template, &variables)
+    }
+
+    /// Render a page with the given error code and client ID for the request.
+    pub fn render(
+        &self,
+        page: Page,
+        error_code: &str,
+        client_id: Option<&str>,
+        http_req: &HttpRequest,
+    ) -> HttpResponse {
+        let (title, message) = page.default_text();
+        let variables = serde_json::json!({
+            "title": title,
+            "message": message,
+            "error": error_code,
+            "client_id": client_id,
+        });
+        let body = self
+            .render_html(
+                page.name(),
+                include_str!("error-page.html"),
+                http_req,
+                variables,
+            )
+            .unwrap_or_else(|e| {
+                error!("Could not render {} page: {}", page.name(), e);
+                message.to_string()
+            });
+        HttpResponseBuilder::new(page.status())
+            .content_type("text/html; charset=utf-8")
+            .body(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepted_languages() {
+        assert_eq!(
+            vec!["de-at", "de", "en"],
+            accepted_languages("en;q=0.5, de-AT, de;q=0.8, *;q=0.1")
+        );
+        assert!(accepted_languages("").is_empty());
+        assert_eq!(
+            vec!["en"],
+            accepted_languages("x/../../other, de-, ../en, en, de--at, *")
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use serde_json::Map;
+
+use crate::{
+    attributes::AttributeValue,
+    settings::{AttributeProfile, HeaderSettings},
+};
+
+/// A claim of an attribute profile and the headers it is taken from.
+pub struct ProfileClaim {
+    pub claim: &'static str,
+    /// Names of the headers with the attribute, the first one that has been
+    /// forwarded is used, e.g. the name of the SAML attribute and the ID of
+    /// the default Shibboleth attribute map
+    pub headers: &'static [&'static str],
+    /// Whether the claim is a list of values instead of a single string
+    pub multi_valued: bool,
+}
+
+const fn single(claim: &'static str, headers: &'static [&'static str]) -> ProfileClaim {
+    ProfileClaim {
+        claim,
+        headers,
+        multi_valued: false,
+    }
+}
+
+const fn multi(claim: &'static str, headers: &'static [&'static str]) -> ProfileClaim {
+    ProfileClaim {
+        claim,
+        headers,
+        multi_valued: true,
+    }
+}
+
+/// eduPerson and other SAML2 attributes mapped to the standard claims of OpenID Connect.
+const EDUPERSON_CLAIMS: &[ProfileClaim] = &[
+    single("name", &["displayName", "cn"]),
+    single("given_name", &["givenName"]),
+    single("family_name", &["sn", "surname"]),
+    single("email", &["mail"]),
+    single("preferred_username", &["eduPersonPrincipalName", "eppn"]),
+    single("nickname", &["eduPersonNickname"]),
+    single("phone_number", &["telephoneNumber"]),
+    single("locale", &["preferredLanguage"]),
+];
+
+/// The claims of the REFEDS mapping of SAML attributes to OpenID Connect
+/// claims, which is also used by GÉANT services.
+const REFEDS_CLAIMS: &[ProfileClaim] = &[
+    single("name", &["displayName", "cn"]),
+    single("given_name", &["givenName"]),
+    single("family_name", &["sn", "surname"]),
+    single("email", &["mail"]),
+    single(
+        "eduperson_principal_name",
+        &["eduPersonPrincipalName", "eppn"],
+    ),
+    single("eduperson_unique_id", &["eduPersonUniqueId"]),
+    multi(
+        "eduperson_affiliation",
+        &["eduPersonAffiliation", "unscoped-affiliation"],
+    ),
+    multi(
+        "eduperson_scoped_affiliation",
+        &["eduPersonScopedAffiliation", "affiliation"],
+    ),
+    multi(
+        "eduperson_entitlement",
+        &["eduPersonEntitlement", "entitlement"],
+    ),
+    multi("eduperson_assurance", &["eduPersonAssurance"]),
+    single("eduperson_orcid", &["eduPersonOrcid"]),
+    single("schac_home_organization", &["schacHomeOrganization"]),
+];
+
+impl AttributeProfile {
+    pub fn claims(&self) -> &'static [ProfileClaim] {
+        match self {
+            AttributeProfile::EduPerson => EDUPERSON_CLAIMS,
+            AttributeProfile::Refeds => REFEDS_CLAIMS,
+        }
+    }
+
+    /// The headers the claims of the profile are taken from.
+    pub fn headers(&self) -> impl Iterator<Item = &'static str> {
+        self.claims()
+            .iter()
+            .flat_map(|claim| claim.headers.iter().copied())
+    }
+
+    /// Create the claims of the profile from the collected attributes.
+    ///
+    /// Attributes that have not been split into lists are split like the
+    /// multi-valued attributes forwarded by Shibboleth. Single-valued claims
+    /// get the first value.
+    pub fn create_claims(
+        &self,
+        attributes: &[(&str, AttributeValue)],
+    ) -> Map<String, serde_json::Value> {
+        let mut claims = Map::new();
+        for profile_claim in self.claims() {
+            let values = profile_claim.headers.iter().find_map(|header| {
+                attributes
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(header))
+                    .map(|(_, value)| attribute_values(value))
+                    .filter(|values| !values.is_empty())
+            });
+            let value = match values {
+                Some(values) if profile_claim.multi_valued => values.into(),
+                Some(mut values) => values.swap_remove(0).into(),
+                None => continue,
+            };
+            claims.insert(profile_claim.claim.to_string(), value);
+        }
+        claims
+    }
+}
+
+fn attribute_values(value: &AttributeValue) -> Vec<String> {
+    let values = match value {
+        AttributeValue::Single(raw) => {
+            match AttributeValue::parse(raw, Some(&HeaderSettings::default())) {
+                AttributeValue::List(values) => values,
+                AttributeValue::Single(value) => vec![value],
+            }
+        }
+        AttributeValue::List(values) => values.clone(),
+    };
+    values.into_iter().filter(|v| !v.is_empty()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_claims() {
+        let attributes = vec![
+            (
+                "mail",
+                AttributeValue::Single("first@example.com;second@example.com".to_string()),
+            ),
+            (
+                "displayName",
+                AttributeValue::Single("Jane Doe".to_string()),
+            ),
+            ("sn", AttributeValue::Single(String::new())),
+            (
+                "affiliation",
+                AttributeValue::Single("member@example.com;staff@example.com".to_string()),
+            ),
+        ];
+
+        let claims = AttributeProfile::EduPerson.create_claims(&attributes);
+        assert_eq!("first@example.com", claims["email"]);
+        assert_eq!("Jane Doe", claims["name"]);
+        assert!(!claims.contains_key("family_name"));
+        assert!(!claims.contains_key("eduperson_scoped_affiliation"));
+
+        let claims = AttributeProfile::Refeds.create_claims(&attributes);
+        assert_eq!(
+            serde_json::json!(["member@example.com", "staff@example.com"]),
+            claims["eduperson_scoped_affiliation"]
+        );
+    }
+}
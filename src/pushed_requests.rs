@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use oxide_auth::endpoint::NormalizedParameter;
+
+use crate::{jwt::generate_jti, settings::PushedAuthorization};
+
+/// Prefix of the `request_uri` that references a pushed authorization request.
+pub const REQUEST_URI_PREFIX: &str = "urn:ietf:params:oauth:request_uri:";
+
+struct PushedRequest {
+    client_id: String,
+    parameters: NormalizedParameter,
+    /// Whether the parameters have been taken from a verified request object
+    signed: bool,
+    until: DateTime<Utc>,
+}
+
+/// Keeps the authorization requests that clients pushed to the `/par`
+/// endpoint (RFC 9126) by their `request_uri`.
+pub struct PushedRequestStore {
+    requests: HashMap<String, PushedRequest>,
+    lifetime: Duration,
+}
+
+impl PushedRequestStore {
+    pub fn new(settings: &PushedAuthorization) -> PushedRequestStore {
+        PushedRequestStore {
+            requests: HashMap::new(),
+            lifetime: Duration::seconds(settings.expires_in),
+        }
+    }
+
+    /// Use new settings for requests that are pushed from now on.
+    pub fn set_settings(&mut self, settings: &PushedAuthorization) {
+        self.lifetime = Duration::seconds(settings.expires_in);
+    }
+
+    /// Store the parameters of an authorization request and return the `request_uri` referencing them.
+    ///
+    /// `signed` tells whether the parameters are the ones of a verified request object.
+    pub fn push(
+        &mut self,
+        client_id: &str,
+        parameters: NormalizedParameter,
+        signed: bool,
+    ) -> String {
+        self.remove_expired();
+        let request_uri = format!("{}{}", REQUEST_URI_PREFIX, generate_jti());
+        self.requests.insert(
+            request_uri.clone(),
+            PushedRequest {
+                client_id: client_id.to_string(),
+                parameters,
+                signed,
+                until: Utc::now() + self.lifetime,
+            },
+        );
+        request_uri
+    }
+
+    /// Get the parameters of a request the client pushed, if it is not
+    /// expired, and whether they were taken from a verified request object.
+    pub fn get(&self, request_uri: &str, client_id: &str) -> Option<(NormalizedParameter, bool)> {
+        let now = Utc::now();
+        self.requests
+            .get(request_uri)
+            .filter(|request| request.client_id == client_id && request.until > now)
+            .map(|request| (request.parameters.clone(), request.signed))
+    }
+
+    /// Remove a request once it has been used, so it can only be used once.
+    pub fn remove(&mut self, request_uri: &str) {
+        self.requests.remove(request_uri);
+    }
+
+    /// The number of pushed requests, including expired ones that have not been removed yet.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    fn remove_expired(&mut self) {
+        let now = Utc::now();
+        self.requests.retain(|_, request| request.until > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oxide_auth::endpoint::QueryParameter;
+
+    fn parameters() -> NormalizedParameter {
+        let mut parameters = NormalizedParameter::new();
+        parameters.insert_or_poison("response_type".into(), "code".into());
+        parameters
+    }
+
+    #[test]
+    fn test_pushed_request() {
+        let mut store = PushedRequestStore::new(&PushedAuthorization::default());
+        let request_uri = store.push("default", parameters(), true);
+        assert!(request_uri.starts_with(REQUEST_URI_PREFIX));
+
+        assert!(store.get(&request_uri, "other-client").is_none());
+        let (parameters, signed) = store.get(&request_uri, "default").unwrap();
+        assert!(signed);
+        assert_eq!(
+            Some("code"),
+            parameters.unique_value("response_type").as_deref()
+        );
+
+        store.remove(&request_uri);
+        assert!(store.get(&request_uri, "default").is_none());
+
+        let mut store = PushedRequestStore::new(&PushedAuthorization {
+            expires_in: 0,
+            ..Default::default()
+        });
+        let request_uri = store.push("default", self::parameters(), false);
+        assert!(store.get(&request_uri, "default").is_none());
+        store.push("default", self::parameters(), false);
+        assert_eq!(1, store.len());
+    }
+}
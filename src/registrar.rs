@@ -0,0 +1,386 @@
+use std::{collections::HashMap, sync::Arc};
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::debug;
+use oxide_auth::primitives::{
+    prelude::*,
+    registrar::{BoundClient, RegisteredUrl, RegistrarError},
+};
+use ring::constant_time::verify_slices_are_equal;
+
+use crate::{
+    errors::StartupError,
+    metrics::{Denial, Metrics},
+    settings::{self, ClientAuthMethod},
+};
+
+/// Algorithms the assertions and request objects of clients may be signed with, depending on their key.
+const RSA_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+];
+const EC_ALGORITHMS: &[Algorithm] = &[Algorithm::ES256, Algorithm::ES384];
+
+/// Hash a client secret with argon2 and a random salt.
+pub fn hash_secret(secret: &str) -> Result<String, StartupError> {
+    let salt: [u8; 16] = rand::random();
+    Ok(argon2::hash_encoded(
+        secret.as_bytes(),
+        &salt,
+        &argon2::Config::default(),
+    )?)
+}
+
+/// Check a secret against a stored secret, which is either an argon2 hash or in plain text.
+pub fn verify_secret(stored: &str, secret: &str) -> bool {
+    if stored.starts_with("$argon2") {
+        argon2::verify_encoded(stored, secret.as_bytes()).unwrap_or(false)
+    } else {
+        verify_slices_are_equal(stored.as_bytes(), secret.as_bytes()).is_ok()
+    }
+}
+
+/// How the credentials of a client are verified.
+enum Credential {
+    /// A public client, which does not have credentials
+    None,
+    /// The argon2 hash of the client secret
+    Secret(String),
+    /// An assertion signed with the private key of the client
+    Assertion,
+}
+
+impl Credential {
+    fn new(client: &settings::Client) -> Result<Credential, StartupError> {
+        let invalid = |reason| StartupError::InvalidClientAuthentication(client.id.clone(), reason);
+        match client.auth_method() {
+            ClientAuthMethod::ClientSecretBasic | ClientAuthMethod::ClientSecretPost => {
+                match &client.secret {
+                    // Secrets that are already hashed, e.g. of registered clients, are kept
+                    Some(secret) if secret.starts_with("$argon2") => {
+                        Ok(Credential::Secret(secret.clone()))
+                    }
+                    Some(secret) => Ok(Credential::Secret(hash_secret(secret)?)),
+                    None => Err(invalid("a secret is required")),
+                }
+            }
+            ClientAuthMethod::PrivateKeyJwt if client.public_key.is_none() => {
+                Err(invalid("a public key is required"))
+            }
+            ClientAuthMethod::PrivateKeyJwt => Ok(Credential::Assertion),
+            ClientAuthMethod::None if client.secret.is_some() => {
+                Err(invalid("public clients must not have a secret"))
+            }
+            ClientAuthMethod::None => Ok(Credential::None),
+        }
+    }
+}
+
+/// The public key of a client and the algorithms it can be used with.
+struct PublicKey {
+    key: DecodingKey,
+    algorithms: &'static [Algorithm],
+}
+
+impl PublicKey {
+    fn new(client: &settings::Client) -> Result<Option<PublicKey>, StartupError> {
+        let pem = match &client.public_key {
+            Some(pem) => pem.as_bytes(),
+            None => return Ok(None),
+        };
+        if let Ok(key) = DecodingKey::from_rsa_pem(pem) {
+            Ok(Some(PublicKey {
+                key,
+                algorithms: RSA_ALGORITHMS,
+            }))
+        } else if let Ok(key) = DecodingKey::from_ec_pem(pem) {
+            Ok(Some(PublicKey {
+                key,
+                algorithms: EC_ALGORITHMS,
+            }))
+        } else {
+            Err(StartupError::InvalidClientAuthentication(
+                client.id.clone(),
+                "the public key is not a PEM encoded RSA or EC key",
+            ))
+        }
+    }
+}
+
+/// Registrar for all known clients, which also keeps the configuration of each client.
+///
+/// In contrast to the [`ClientMap`] it wraps, it grants the requested scopes
+/// as far as they are allowed for the client, instead of always overriding
+/// them with the default scope.
+pub struct ClientRegistry {
+    clients: ClientMap,
+    settings: HashMap<String, settings::Client>,
+    credentials: HashMap<String, Credential>,
+    public_keys: HashMap<String, PublicKey>,
+    client_assertion: settings::ClientAssertion,
+    metrics: Arc<Metrics>,
+}
+
+impl ClientRegistry {
+    pub fn new(metrics: Arc<Metrics>) -> ClientRegistry {
+        ClientRegistry {
+            clients: ClientMap::new(),
+            settings: HashMap::new(),
+            credentials: HashMap::new(),
+            public_keys: HashMap::new(),
+            client_assertion: settings::ClientAssertion::default(),
+            metrics,
+        }
+    }
+
+    /// Set the audience and leeway the assertions of clients are checked with.
+    pub fn set_client_assertion(&mut self, settings: &settings::ClientAssertion) {
+        self.client_assertion = settings.clone();
+    }
+
+    /// Register a client or replace an existing client with the same ID.
+    pub fn register_client(&mut self, client: &settings::Client) -> Result<(), StartupError> {
+        let credential = Credential::new(client)?;
+        let public_key = PublicKey::new(client)?;
+        self.clients.register_client(Self::create_client(client)?);
+        self.settings.insert(client.id.clone(), client.clone());
+        self.credentials.insert(client.id.clone(), credential);
+        match public_key {
+            Some(public_key) => self.public_keys.insert(client.id.clone(), public_key),
+            None => self.public_keys.remove(&client.id),
+        };
+        Ok(())
+    }
+
+    /// Remove a client, so it can not be used anymore.
+    pub fn unregister_client(&mut self, client_id: &str) -> Option<settings::Client> {
+        let removed = self.settings.remove(client_id)?;
+        self.credentials.remove(client_id);
+        self.public_keys.remove(client_id);
+        // The client map does not support removing clients, so it is created again
+        self.clients = self
+            .settings
+            .values()
+            .filter_map(|client| Self::create_client(client).ok())
+            .collect();
+        Some(removed)
+    }
+
+    fn create_client(client: &settings::Client) -> Result<Client, StartupError> {
+        let additional_redirect_uris: Vec<_> = client
+            .additional_redirect_uris
+            .iter()
+            .filter_map(|u| u.parse::<url::Url>().ok())
+            .map(RegisteredUrl::Semantic)
+            .collect();
+        let redirect_uri = client.redirect_uri.parse::<url::Url>()?.into();
+        let default_scope = client.default_scope()?;
+        // Make sure the allowed scopes are valid, too
+        client.allowed_scope()?;
+        if let Some(scope) = client
+            .default_scopes
+            .iter()
+            .find(|scope| !client.allowed_scopes.contains(scope))
+        {
+            return Err(StartupError::DefaultScopeNotAllowed(
+                client.id.clone(),
+                scope.clone(),
+            ));
+        }
+
+        // The credentials are checked by the registry itself, so the client map
+        // is only used for the redirect URIs
+        Ok(Client::public(&client.id, redirect_uri, default_scope)
+            .with_additional_redirect_uris(additional_redirect_uris))
+    }
+
+    /// Get the configuration of a registered client.
+    pub fn client(&self, client_id: &str) -> Option<&settings::Client> {
+        self.settings.get(client_id)
+    }
+
+    /// Check if a client is registered with the given authentication method.
+    pub fn allows_auth_method(&self, client_id: &str, method: ClientAuthMethod) -> bool {
+        let allowed = self
+            .client(client_id)
+            .is_some_and(|client| client.auth_method() == method);
+        if !allowed {
+            debug!(
+                "Client {} did not authenticate with its registered method",
+                client_id
+            );
+            self.metrics.record_denial(Denial::InvalidClientCredentials);
+        }
+        allowed
+    }
+
+    /// Decode a JWT the client signed with its private key.
+    fn decode_signed(
+        &self,
+        client_id: &str,
+        token: &str,
+        mut validation: Validation,
+    ) -> Option<serde_json::Map<String, serde_json::Value>> {
+        let public_key = self.public_keys.get(client_id)?;
+        validation.algorithms = public_key.algorithms.to_vec();
+        jsonwebtoken::decode(token, &public_key.key, &validation)
+            .inspect_err(|e| debug!("Invalid JWT of client {}: {}", client_id, e))
+            .map(|token| token.claims)
+            .ok()
+    }
+
+    /// Verify the assertion a client signed with its private key (RFC 7523).
+    fn verify_assertion(&self, client_id: &str, assertion: &[u8]) -> bool {
+        let mut validation = Validation::default();
+        validation.set_audience(&[&self.client_assertion.audience]);
+        validation.set_issuer(&[client_id]);
+        validation.sub = Some(client_id.to_string());
+        validation.set_required_spec_claims(&["exp", "aud", "iss", "sub"]);
+        validation.leeway = self.client_assertion.leeway;
+        let assertion = String::from_utf8_lossy(assertion);
+        self.decode_signed(client_id, &assertion, validation)
+            .is_some()
+    }
+
+    /// Verify a request object of a client (RFC 9101) and return its claims.
+    ///
+    /// The request object has to be signed with the key of the client, has to
+    /// be intended for this server and has to expire.
+    pub fn verify_request_object(
+        &self,
+        client_id: &str,
+        request: &str,
+        audience: &str,
+    ) -> Option<serde_json::Map<String, serde_json::Value>> {
+        let mut validation = Validation::default();
+        validation.set_audience(&[audience]);
+        validation.set_issuer(&[client_id]);
+        validation.set_required_spec_claims(&["exp", "aud", "iss"]);
+        validation.leeway = self.client_assertion.leeway;
+        let claims = self.decode_signed(client_id, request, validation);
+        if claims.is_none() {
+            self.metrics.record_denial(Denial::InvalidRequestObject);
+        }
+        claims
+    }
+
+    /// The configuration of all registered clients, ordered by their ID.
+    pub fn clients(&self) -> Vec<&settings::Client> {
+        let mut clients: Vec<_> = self.settings.values().collect();
+        clients.sort_by(|a, b| a.id.cmp(&b.id));
+        clients
+    }
+}
+
+impl Registrar for ClientRegistry {
+    fn bound_redirect<'a>(&self, bound: ClientUrl<'a>) -> Result<BoundClient<'a>, RegistrarError> {
+        self.clients.bound_redirect(bound).inspect_err(|_| {
+            self.metrics.record_denial(Denial::InvalidRedirect);
+        })
+    }
+
+    fn negotiate(
+        &self,
+        bound: BoundClient,
+        scope: Option<Scope>,
+    ) -> Result<PreGrant, RegistrarError> {
+        let client = self
+            .client(bound.client_id.as_ref())
+            .ok_or(RegistrarError::Unspecified)?;
+        let scope = match scope {
+            Some(requested) => {
+                // Only grant the requested scopes that are allowed for this client
+                let granted: Vec<&str> = requested
+                    .iter()
+                    .filter(|s| client.allowed_scopes.iter().any(|allowed| allowed == s))
+                    .collect();
+                if granted.is_empty() {
+                    return Err(RegistrarError::Unspecified);
+                }
+                granted
+                    .join(" ")
+                    .parse()
+                    .map_err(|_| RegistrarError::PrimitiveError)?
+            }
+            None => client
+                .default_scope()
+                .map_err(|_| RegistrarError::PrimitiveError)?,
+        };
+        Ok(PreGrant {
+            client_id: bound.client_id.into_owned(),
+            redirect_uri: bound.redirect_uri.into_owned(),
+            scope,
+        })
+    }
+
+    /// Check the secret or the assertion of a client, depending on how it is registered.
+    fn check(&self, client_id: &str, passphrase: Option<&[u8]>) -> Result<(), RegistrarError> {
+        let valid = match (self.credentials.get(client_id), passphrase) {
+            (Some(Credential::None), None) => true,
+            (Some(Credential::Secret(hash)), Some(secret)) => {
+                argon2::verify_encoded(hash, secret).unwrap_or(false)
+            }
+            (Some(Credential::Assertion), Some(assertion)) => {
+                self.verify_assertion(client_id, assertion)
+            }
+            _ => false,
+        };
+        if valid {
+            Ok(())
+        } else {
+            self.metrics.record_denial(Denial::InvalidClientCredentials);
+            Err(RegistrarError::Unspecified)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_negotiate_scope() {
+        let mut registry = ClientRegistry::new(Arc::default());
+        registry
+            .register_client(&settings::Client {
+                allowed_scopes: vec!["default-scope".to_string(), "email".to_string()],
+                ..Default::default()
+            })
+            .unwrap();
+        let bound = || BoundClient {
+            client_id: Cow::Borrowed("default"),
+            redirect_uri: Cow::Owned("http://localhost:8080".parse::<url::Url>().unwrap().into()),
+        };
+
+        let pre_grant = registry.negotiate(bound(), None).unwrap();
+        assert_eq!("default-scope", pre_grant.scope.to_string());
+
+        let pre_grant = registry
+            .negotiate(bound(), Some("email admin".parse().unwrap()))
+            .unwrap();
+        assert_eq!("email", pre_grant.scope.to_string());
+
+        assert!(registry
+            .negotiate(bound(), Some("admin".parse().unwrap()))
+            .is_err());
+    }
+
+    #[test]
+    fn test_default_scope_not_allowed() {
+        let mut registry = ClientRegistry::new(Arc::default());
+        let result = registry.register_client(&settings::Client {
+            allowed_scopes: vec!["email".to_string()],
+            default_scopes: vec!["email".to_string(), "admin".to_string()],
+            ..Default::default()
+        });
+        assert!(matches!(
+            result,
+            Err(StartupError::DefaultScopeNotAllowed(_, scope)) if scope == "admin"
+        ));
+    }
+}
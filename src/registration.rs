@@ -0,0 +1,376 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use log::info;
+use ring::constant_time::verify_slices_are_equal;
+use serde::Deserialize;
+
+use crate::{
+    api::{DEVICE_CODE_GRANT_TYPE, TOKEN_EXCHANGE_GRANT_TYPE},
+    client_store::{ClientMetadata, RegisteredClient},
+    errors::{RegistrationError, StartupError},
+    jwt::generate_jti,
+    registrar::{hash_secret, verify_secret},
+    settings::{self, ClientAuthMethod, Settings},
+    state::State,
+};
+
+const SUPPORTED_GRANT_TYPES: &[&str] = &[
+    "authorization_code",
+    "refresh_token",
+    DEVICE_CODE_GRANT_TYPE,
+    TOKEN_EXCHANGE_GRANT_TYPE,
+];
+
+const SUPPORTED_AUTH_METHODS: &[ClientAuthMethod] = &[
+    ClientAuthMethod::None,
+    ClientAuthMethod::ClientSecretBasic,
+    ClientAuthMethod::ClientSecretPost,
+];
+
+/// Register the routes of the client registration and management endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/register").route(web::post().to(register)))
+        .service(
+            web::resource("/register/{client_id}")
+                .route(web::get().to(read))
+                .route(web::put().to(update))
+                .route(web::delete().to(delete)),
+        );
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// A random secret for a client.
+fn generate_secret() -> String {
+    format!("{}{}", generate_jti(), generate_jti())
+}
+
+/// Check the initial access token that is required to register a new client.
+fn check_initial_access_token(
+    req: &HttpRequest,
+    settings: &Settings,
+) -> Result<(), RegistrationError> {
+    let expected = match &settings.registration.initial_access_token {
+        Some(token) if settings.registration.enabled && !token.is_empty() => token,
+        _ => return Err(RegistrationError::Disabled),
+    };
+    let token = bearer_token(req).ok_or(RegistrationError::InvalidToken)?;
+    verify_slices_are_equal(token.as_bytes(), expected.as_bytes())
+        .map_err(|_| RegistrationError::InvalidToken)
+}
+
+/// Get a registered client with the registration access token of the request.
+///
+/// Unknown clients are rejected like invalid tokens, so it can not be found
+/// out which clients exist.
+fn authenticate(
+    req: &HttpRequest,
+    client_id: &str,
+    state: &State,
+) -> Result<RegisteredClient, RegistrationError> {
+    if !state.settings().registration.enabled {
+        return Err(RegistrationError::Disabled);
+    }
+    let token = bearer_token(req).ok_or(RegistrationError::InvalidToken)?;
+    state
+        .client_store()
+        .get(client_id)
+        .filter(|registered| registered.verify_registration_token(token))
+        .cloned()
+        .ok_or(RegistrationError::InvalidToken)
+}
+
+/// Redirect URIs have to use HTTPS, except for native applications on the same device.
+fn check_redirect_uri(uri: &str) -> Result<(), RegistrationError> {
+    let url = url::Url::parse(uri).map_err(|_| {
+        RegistrationError::InvalidRedirectUri(format!("{} is not a valid URI", uri))
+    })?;
+    if url.fragment().is_some() {
+        return Err(RegistrationError::InvalidRedirectUri(format!(
+            "{} must not contain a fragment",
+            uri
+        )));
+    }
+    let loopback = matches!(
+        url.host_str(),
+        Some("localhost") | Some("127.0.0.1") | Some("[::1]")
+    );
+    if url.scheme() != "https" && !(url.scheme() == "http" && loopback) {
+        return Err(RegistrationError::InvalidRedirectUri(format!(
+            "{} must use https",
+            uri
+        )));
+    }
+    Ok(())
+}
+
+/// The authentication method a client wants to use, if it is supported.
+fn auth_method(metadata: &ClientMetadata) -> Result<ClientAuthMethod, RegistrationError> {
+    SUPPORTED_AUTH_METHODS
+        .iter()
+        .find(|method| method.as_str() == metadata.token_endpoint_auth_method)
+        .copied()
+        .ok_or_else(|| {
+            RegistrationError::InvalidClientMetadata(format!(
+                "Unsupported token_endpoint_auth_method {}",
+                metadata.token_endpoint_auth_method
+            ))
+        })
+}
+
+/// Check the metadata of a client and create its configuration.
+///
+/// Registered clients get the settings of the `[registration.client]`
+/// template, e.g. the identity source and the token lifetimes, and can only
+/// use the registered grant types. They always have to ask the user for consent.
+fn create_client(
+    registered: &RegisteredClient,
+    settings: &Settings,
+) -> Result<settings::Client, RegistrationError> {
+    let metadata = &registered.metadata;
+    let method = auth_method(metadata)?;
+    if let Some(grant_type) = metadata
+        .grant_types
+        .iter()
+        .find(|grant_type| !SUPPORTED_GRANT_TYPES.contains(&grant_type.as_str()))
+    {
+        return Err(RegistrationError::InvalidClientMetadata(format!(
+            "Unsupported grant type {}",
+            grant_type
+        )));
+    }
+    if metadata.response_types.iter().any(|t| t != "code")
+        || (metadata
+            .grant_types
+            .iter()
+            .any(|t| t == "authorization_code")
+            && metadata.response_types.is_empty())
+    {
+        return Err(RegistrationError::InvalidClientMetadata(
+            "Only the response type code is supported".to_string(),
+        ));
+    }
+
+    let (redirect_uri, additional_redirect_uris) = match metadata.redirect_uris.split_first() {
+        Some((first, rest)) => (first.clone(), rest.to_vec()),
+        None => {
+            return Err(RegistrationError::InvalidRedirectUri(
+                "At least one redirect URI is required".to_string(),
+            ))
+        }
+    };
+    for uri in metadata
+        .redirect_uris
+        .iter()
+        .chain(&metadata.post_logout_redirect_uris)
+    {
+        check_redirect_uri(uri)?;
+    }
+
+    let allowed_scopes = &settings.registration.allowed_scopes;
+    let scopes: Vec<String> = match &metadata.scope {
+        Some(scope) => scope.split_whitespace().map(|s| s.to_string()).collect(),
+        None => allowed_scopes.clone(),
+    };
+    if let Some(scope) = scopes.iter().find(|s| !allowed_scopes.contains(s)) {
+        return Err(RegistrationError::InvalidClientMetadata(format!(
+            "The scope {} is not allowed",
+            scope
+        )));
+    }
+
+    let template = &settings.registration.client;
+    Ok(settings::Client {
+        id: registered.client_id.clone(),
+        redirect_uri,
+        additional_redirect_uris,
+        secret: registered.secret.clone(),
+        allowed_scopes: scopes.clone(),
+        default_scopes: scopes,
+        post_logout_redirect_uris: metadata.post_logout_redirect_uris.clone(),
+        require_consent: true,
+        access_token_lifetime: template.access_token_lifetime,
+        refresh_token_lifetime: template.refresh_token_lifetime,
+        max_attribute_age: template.max_attribute_age,
+        refresh_chain_lifetime: template.refresh_chain_lifetime,
+        token_endpoint_auth_method: Some(method),
+        response_modes: template.response_modes.clone(),
+        require_state: template.require_state,
+        require_nonce: template.require_nonce,
+        grant_types: Some(metadata.grant_types.clone()),
+        identity_source: template.identity_source.clone(),
+        ..Default::default()
+    })
+}
+
+/// Create the configuration of a stored client with the current settings,
+/// when the server is started or the settings are reloaded.
+pub fn load_client(
+    registered: &RegisteredClient,
+    settings: &Settings,
+) -> Result<settings::Client, StartupError> {
+    create_client(registered, settings).map_err(|e| {
+        StartupError::InvalidRegisteredClient(registered.client_id.clone(), e.to_string())
+    })
+}
+
+fn parse_metadata<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, RegistrationError> {
+    serde_json::from_slice(body)
+        .map_err(|e| RegistrationError::InvalidClientMetadata(e.to_string()))
+}
+
+/// The client information response, which contains the metadata and the credentials of the client.
+///
+/// Only the hash of the secret is stored, so the secret is only included when it has been generated.
+fn client_information(
+    registered: &RegisteredClient,
+    client: &settings::Client,
+    client_secret: Option<&str>,
+    registration_token: Option<&str>,
+    settings: &Settings,
+) -> serde_json::Value {
+    let mut response = serde_json::to_value(&registered.metadata).unwrap_or_default();
+    response["client_id"] = client.id.clone().into();
+    response["client_id_issued_at"] = registered.issued_at.into();
+    response["scope"] = client.allowed_scopes.join(" ").into();
+    if let Some(secret) = client_secret {
+        response["client_secret"] = secret.into();
+    }
+    if client.secret.is_some() {
+        response["client_secret_expires_at"] = 0.into();
+    }
+    response["registration_client_uri"] = format!(
+        "{}/{}",
+        settings.registration.endpoint.trim_end_matches('/'),
+        client.id
+    )
+    .into();
+    if let Some(token) = registration_token {
+        response["registration_access_token"] = token.into();
+    }
+    response
+}
+
+/// Register a new client (RFC 7591).
+pub async fn register(
+    (req, body, state): (HttpRequest, web::Bytes, web::Data<State>),
+) -> Result<HttpResponse, RegistrationError> {
+    let settings = state.settings();
+    check_initial_access_token(&req, &settings)?;
+    let metadata: ClientMetadata = parse_metadata(&body)?;
+
+    let client_id = generate_jti();
+    let secret = (auth_method(&metadata)? != ClientAuthMethod::None).then(generate_secret);
+    let hashed_secret = secret.as_deref().map(hash_secret).transpose()?;
+    let registration_token = generate_secret();
+    let registered = RegisteredClient::new(
+        &client_id,
+        hashed_secret,
+        metadata,
+        Utc::now().timestamp(),
+        &registration_token,
+    );
+    let client = create_client(&registered, &settings)?;
+
+    state.register_client(&client)?;
+    if let Err(e) = state.client_store().insert(registered.clone()) {
+        state.unregister_client(&client_id);
+        return Err(e.into());
+    }
+    info!("Registered client {}", client_id);
+    Ok(HttpResponse::Created().json(client_information(
+        &registered,
+        &client,
+        secret.as_deref(),
+        Some(&registration_token),
+        &settings,
+    )))
+}
+
+/// Read the registration of a client (RFC 7592).
+pub async fn read(
+    (req, client_id, state): (HttpRequest, web::Path<String>, web::Data<State>),
+) -> Result<HttpResponse, RegistrationError> {
+    let registered = authenticate(&req, &client_id, &state)?;
+    let settings = state.settings();
+    let client = create_client(&registered, &settings)?;
+    Ok(HttpResponse::Ok().json(client_information(
+        &registered,
+        &client,
+        None,
+        None,
+        &settings,
+    )))
+}
+
+#[derive(Deserialize)]
+struct UpdateRequest {
+    client_id: String,
+    client_secret: Option<String>,
+    #[serde(flatten)]
+    metadata: ClientMetadata,
+}
+
+/// Replace the metadata of a registered client (RFC 7592).
+pub async fn update(
+    (req, client_id, body, state): (HttpRequest, web::Path<String>, web::Bytes, web::Data<State>),
+) -> Result<HttpResponse, RegistrationError> {
+    let mut registered = authenticate(&req, &client_id, &state)?;
+    let request: UpdateRequest = parse_metadata(&body)?;
+    if request.client_id != *client_id {
+        return Err(RegistrationError::InvalidClientMetadata(
+            "The client_id does not match the registered client".to_string(),
+        ));
+    }
+    let secret_matches = match (&request.client_secret, &registered.secret) {
+        (Some(secret), Some(stored)) => verify_secret(stored, secret),
+        (Some(_), None) => false,
+        (None, _) => true,
+    };
+    if !secret_matches {
+        return Err(RegistrationError::InvalidClientMetadata(
+            "The client_secret does not match the registered client".to_string(),
+        ));
+    }
+
+    // Keep the secret, unless the client changes to or from a public client
+    let mut new_secret = None;
+    registered.secret = match (auth_method(&request.metadata)?, &registered.secret) {
+        (ClientAuthMethod::None, _) => None,
+        (_, Some(stored)) => Some(stored.clone()),
+        (_, None) => Some(hash_secret(new_secret.insert(generate_secret()))?),
+    };
+    registered.metadata = request.metadata;
+    let settings = state.settings();
+    let client = create_client(&registered, &settings)?;
+
+    state.register_client(&client)?;
+    state.client_store().insert(registered.clone())?;
+    info!("Updated registration of client {}", client_id);
+    Ok(HttpResponse::Ok().json(client_information(
+        &registered,
+        &client,
+        new_secret.as_deref(),
+        None,
+        &settings,
+    )))
+}
+
+/// Remove a registered client and revoke its refresh tokens (RFC 7592).
+pub async fn delete(
+    (req, client_id, state): (HttpRequest, web::Path<String>, web::Data<State>),
+) -> Result<HttpResponse, RegistrationError> {
+    authenticate(&req, &client_id, &state)?;
+    state.client_store().remove(&client_id)?;
+    let revoked = state.unregister_client(&client_id);
+    info!(
+        "Removed client {} and revoked {} refresh tokens",
+        client_id, revoked
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
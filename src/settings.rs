@@ -1,19 +1,156 @@
+use std::collections::HashMap;
 use std::io::Write;
+use std::net::IpAddr;
 use std::ops::Deref;
 use tempfile::NamedTempFile;
 
 use jsonwebtoken::{DecodingKey, EncodingKey};
+use oxide_auth::primitives::scope::Scope;
 use serde::{Deserialize, Serialize};
 
+use crate::attributes::{glob_matches, normalize_variable_name, AttributeValue};
 use crate::errors::{RuntimeError, StartupError};
 
+/// Headers and claims that are only included in the token if a specific scope was granted.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ScopeMapping {
+    pub headers: Vec<String>,
+    pub claims: serde_json::Map<String, serde_json::Value>,
+}
+
+/// How the delimiter is escaped inside the values of a multi-valued header.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderEscaping {
+    None,
+    /// A backslash escapes the next character, e.g. `\;` as used by Shibboleth
+    Backslash,
+}
+
+/// How the values of a header are represented in the token.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderOutput {
+    /// A single string with the values joined by the delimiter
+    String,
+    /// A list of strings
+    List,
+}
+
+/// Settings for headers with multiple values, like the attributes forwarded by Shibboleth.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct HeaderSettings {
+    pub delimiter: Option<String>,
+    pub escape: HeaderEscaping,
+    pub deduplicate: bool,
+    pub output: HeaderOutput,
+}
+
+impl Default for HeaderSettings {
+    fn default() -> Self {
+        HeaderSettings {
+            delimiter: Some(";".to_string()),
+            escape: HeaderEscaping::Backslash,
+            deduplicate: false,
+            output: HeaderOutput::List,
+        }
+    }
+}
+
+/// A header that is included in the token, given either by its name or as
+/// table with a name, that can contain `*` as wildcard, and an alternative
+/// variable name.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum IncludeHeader {
+    Name(String),
+    Renamed {
+        name: String,
+        #[serde(rename = "as", skip_serializing_if = "Option::is_none")]
+        alias: Option<String>,
+    },
+}
+
+impl IncludeHeader {
+    pub fn name(&self) -> &str {
+        match self {
+            IncludeHeader::Name(name) | IncludeHeader::Renamed { name, .. } => name,
+        }
+    }
+
+    pub fn alias(&self) -> Option<&str> {
+        match self {
+            IncludeHeader::Name(_) => None,
+            IncludeHeader::Renamed { alias, .. } => alias.as_deref(),
+        }
+    }
+
+    pub fn is_pattern(&self) -> bool {
+        self.name().contains('*')
+    }
+
+    pub fn matches(&self, header: &str) -> bool {
+        glob_matches(self.name(), header)
+    }
+
+    /// The names under which the value of the given header is available in the token template.
+    ///
+    /// Exact header names are also available under their original name, so
+    /// existing templates still work.
+    pub fn variable_names(&self, header: &str) -> Vec<String> {
+        let name = self.name();
+        match (self.is_pattern(), self.alias()) {
+            (false, Some(alias)) => vec![alias.to_string()],
+            (false, None) => {
+                let normalized = normalize_variable_name(name);
+                if normalized == name {
+                    vec![normalized]
+                } else {
+                    vec![name.to_string(), normalized]
+                }
+            }
+            (true, Some(alias)) => {
+                // Replace the literal prefix of the pattern with the alias
+                let prefix_len = name.find('*').unwrap_or_default();
+                let rest = header.get(prefix_len..).unwrap_or_default();
+                vec![format!("{}{}", alias, normalize_variable_name(rest))]
+            }
+            (true, None) => vec![normalize_variable_name(header)],
+        }
+    }
+}
+
+impl From<&str> for IncludeHeader {
+    fn from(name: &str) -> Self {
+        IncludeHeader::Name(name.to_string())
+    }
+}
+
+/// A built-in mapping of SAML attributes to claims.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttributeProfile {
+    /// eduPerson attributes as standard claims of OpenID Connect
+    EduPerson,
+    /// The REFEDS mapping of SAML attributes to OpenID Connect claims
+    Refeds,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Mapping {
     pub token_template: Option<String>,
-    pub include_headers: Vec<String>,
+    pub include_headers: Vec<IncludeHeader>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sub_header: Option<String>,
     pub default_sub: String,
+    /// Attribute profile whose headers are included and whose claims are
+    /// added to the token, unless the template sets them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<AttributeProfile>,
+    pub headers: HashMap<String, HeaderSettings>,
+    pub scopes: HashMap<String, ScopeMapping>,
 }
 
 impl Default for Mapping {
@@ -23,13 +160,203 @@ impl Default for Mapping {
             include_headers: vec![],
             sub_header: None,
             default_sub: "user".to_string(),
+            profile: None,
+            headers: HashMap::default(),
+            scopes: HashMap::default(),
+        }
+    }
+}
+
+impl Mapping {
+    /// Get the settings for a multi-valued header, if there are any.
+    pub fn header_settings(&self, header: &str) -> Option<&HeaderSettings> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(header))
+            .map(|(_, settings)| settings)
+    }
+
+    /// Get the name under which a forwarded header is stored, if it should be
+    /// collected when authorizing, regardless of the granted scope.
+    ///
+    /// For headers that are configured with their exact name, the configured
+    /// name is used, otherwise the name of the forwarded header. The headers
+    /// of the attribute profile are collected as if they were included.
+    pub fn collected_header_name(&self, header: &str) -> Option<String> {
+        let configured = self
+            .include_headers
+            .iter()
+            .map(|h| h.name())
+            .chain(
+                self.scopes
+                    .values()
+                    .flat_map(|m| m.headers.iter().map(String::as_str)),
+            )
+            .chain(
+                self.profile
+                    .iter()
+                    .flat_map(|profile| profile.headers())
+                    .map(|header| -> &str { header }),
+            );
+        let mut matching_pattern = false;
+        for pattern in configured {
+            if !pattern.contains('*') && pattern.eq_ignore_ascii_case(header) {
+                return Some(pattern.to_string());
+            }
+            matching_pattern = matching_pattern || glob_matches(pattern, header);
+        }
+        if matching_pattern {
+            Some(header.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// The names under which a collected header is available in the token template.
+    pub fn variable_names(&self, header: &str) -> Vec<String> {
+        if let Some(include_header) = self.include_headers.iter().find(|h| h.matches(header)) {
+            include_header.variable_names(header)
+        } else {
+            IncludeHeader::from(header).variable_names(header)
+        }
+    }
+
+    /// Checks if a header can be used in the token for the granted scope.
+    ///
+    /// Headers that are only configured for specific scopes are hidden unless
+    /// one of these scopes has been granted.
+    pub fn is_header_visible(&self, header: &str, granted: &Scope) -> bool {
+        if self.include_headers.iter().any(|h| h.matches(header)) {
+            return true;
+        }
+        let restricting_scopes: Vec<&String> = self
+            .scopes
+            .iter()
+            .filter(|(_, m)| m.headers.iter().any(|h| glob_matches(h, header)))
+            .map(|(scope, _)| scope)
+            .collect();
+        restricting_scopes.is_empty()
+            || restricting_scopes
+                .iter()
+                .any(|scope| granted.iter().any(|g| g == scope.as_str()))
+    }
+}
+
+/// Verbosity of the log output.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Where the log messages are written to.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum LogOutput {
+    /// Errors to stderr and all other messages to stdout
+    #[default]
+    Terminal,
+    /// A file that is rotated when it exceeds a maximum size
+    File {
+        path: String,
+        /// Maximum size of the file in bytes before it is rotated
+        #[serde(default = "default_log_max_size")]
+        max_size: u64,
+        /// Number of rotated files that are kept
+        #[serde(default = "default_log_max_files")]
+        max_files: usize,
+    },
+    /// The local syslog socket, which is also read by journald, only on Unix
+    Syslog {
+        #[serde(default = "default_syslog_socket")]
+        socket: String,
+    },
+}
+
+fn default_log_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_log_max_files() -> usize {
+    5
+}
+
+fn default_syslog_socket() -> String {
+    "/dev/log".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct Logging {
+    /// Shortcut for setting the level to `debug` and logging all requests
     pub debug: bool,
+    pub level: LogLevel,
+    pub format: LogFormat,
+    /// Log levels for specific modules, e.g. `actix_web = "info"`
+    pub modules: HashMap<String, LogLevel>,
+    pub output: LogOutput,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            debug: false,
+            level: LogLevel::Info,
+            format: LogFormat::Text,
+            modules: HashMap::default(),
+            output: LogOutput::default(),
+        }
+    }
+}
+
+impl Logging {
+    /// The log level for all modules without a specific level.
+    pub fn default_level(&self) -> LogLevel {
+        if self.debug {
+            LogLevel::Debug
+        } else {
+            self.level
+        }
+    }
+
+    /// The configured levels for specific modules.
+    ///
+    /// Unless in debug mode, the request logging of actix-web is disabled if not configured otherwise.
+    pub fn module_levels(&self) -> HashMap<String, LogLevel> {
+        let mut modules = self.modules.clone();
+        if !self.debug {
+            modules
+                .entry("actix_web".to_string())
+                .or_insert(LogLevel::Warn);
+        }
+        modules
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -47,7 +374,7 @@ impl Default for Bind {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(tag = "type")]
 pub enum JWTVerification {
     HS256 {
@@ -100,13 +427,111 @@ impl Default for JWTVerification {
     }
 }
 
+/// Where the identity of the user and their attributes are taken from when authorizing.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum IdentitySource {
+    /// Plain HTTP headers set by the proxy, as configured in the `[mapping]` section
+    #[default]
+    Headers,
+    /// A JWS signed with a secret shared with the proxy (HS256), with the
+    /// attributes as additional claims
+    Assertion {
+        #[serde(default = "default_assertion_header")]
+        header: String,
+        secret: String,
+        /// The expected `aud` claim
+        audience: String,
+        /// Allowed clock skew in seconds when checking the expiration
+        #[serde(default)]
+        leeway: u64,
+    },
+    /// A fixed identity, e.g. for testing
+    Static {
+        sub: String,
+        #[serde(default)]
+        attributes: HashMap<String, AttributeValue>,
+    },
+}
+
+fn default_assertion_header() -> String {
+    "X-Identity-Assertion".to_string()
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Client {
     pub id: String,
     pub redirect_uri: String,
     pub additional_redirect_uris: Vec<String>,
+    /// The client secret, either in plain text or as argon2 hash
     pub secret: Option<String>,
+    /// Scopes the client is allowed to request
+    pub allowed_scopes: Vec<String>,
+    /// Scopes that are granted if the client does not request any
+    pub default_scopes: Vec<String>,
+    /// URIs the user may be redirected to after logging out
+    #[serde(default)]
+    pub post_logout_redirect_uris: Vec<String>,
+    /// Ask the user to approve sharing their attributes with this client
+    #[serde(default)]
+    pub require_consent: bool,
+    /// Lifetime of an access token in seconds
+    #[serde(default = "default_access_token_lifetime")]
+    pub access_token_lifetime: i64,
+    /// Lifetime of a refresh token in seconds, which starts again when it is rotated
+    #[serde(default = "default_refresh_token_lifetime")]
+    pub refresh_token_lifetime: i64,
+    /// Maximum age in seconds of the attributes read when the user logged in,
+    /// after which tokens can not be refreshed and the user has to log in again
+    #[serde(default)]
+    pub max_attribute_age: Option<i64>,
+    /// Maximum time in seconds after logging in during which refresh tokens can be used
+    #[serde(default)]
+    pub refresh_chain_lifetime: Option<i64>,
+    /// How the client authenticates at the token endpoint, `client_secret_basic`
+    /// if a secret is configured and `none` otherwise
+    #[serde(default)]
+    pub token_endpoint_auth_method: Option<ClientAuthMethod>,
+    /// PEM encoded RSA or EC public key the assertions of a client using
+    /// `private_key_jwt` and its request objects are verified with
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// Only accept authorization requests with a signed request object
+    #[serde(default)]
+    pub require_request_object: bool,
+    /// How the authorization response may be returned to the client, the
+    /// first one is used if the client does not request a `response_mode`
+    #[serde(default = "default_response_modes")]
+    pub response_modes: Vec<ResponseMode>,
+    /// Only accept authorization requests with a `state` parameter
+    #[serde(default)]
+    pub require_state: bool,
+    /// Only accept authorization requests with a `nonce` parameter, which is
+    /// available as `nonce` in the token template
+    #[serde(default)]
+    pub require_nonce: bool,
+    /// Grant types the client may use at the token endpoint, all if not set
+    #[serde(default)]
+    pub grant_types: Option<Vec<String>>,
     pub token_verification: JWTVerification,
+    #[serde(default)]
+    pub identity_source: IdentitySource,
+}
+
+fn default_access_token_lifetime() -> i64 {
+    60 * 60
+}
+
+fn default_refresh_token_lifetime() -> i64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_response_modes() -> Vec<ResponseMode> {
+    vec![
+        ResponseMode::Query,
+        ResponseMode::Fragment,
+        ResponseMode::FormPost,
+    ]
 }
 
 impl Default for Client {
@@ -116,17 +541,376 @@ impl Default for Client {
             redirect_uri: "http://localhost:8080".to_string(),
             additional_redirect_uris: Vec::default(),
             secret: None,
+            allowed_scopes: vec!["default-scope".to_string()],
+            default_scopes: vec!["default-scope".to_string()],
+            post_logout_redirect_uris: Vec::default(),
+            require_consent: false,
+            access_token_lifetime: default_access_token_lifetime(),
+            refresh_token_lifetime: default_refresh_token_lifetime(),
+            max_attribute_age: None,
+            refresh_chain_lifetime: None,
+            token_endpoint_auth_method: None,
+            public_key: None,
+            require_request_object: false,
+            response_modes: default_response_modes(),
+            require_state: false,
+            require_nonce: false,
+            grant_types: None,
             token_verification: JWTVerification::default(),
+            identity_source: IdentitySource::default(),
         }
     }
 }
 
+impl Client {
+    pub fn default_scope(&self) -> Result<Scope, StartupError> {
+        Ok(self.default_scopes.join(" ").parse()?)
+    }
+
+    pub fn allowed_scope(&self) -> Result<Scope, StartupError> {
+        Ok(self.allowed_scopes.join(" ").parse()?)
+    }
+
+    /// Whether the client may use a grant type at the token endpoint.
+    pub fn allows_grant_type(&self, grant_type: &str) -> bool {
+        self.grant_types.as_ref().map_or(true, |grant_types| {
+            grant_types.iter().any(|g| g == grant_type)
+        })
+    }
+
+    /// The configured authentication method or the one implied by the secret.
+    pub fn auth_method(&self) -> ClientAuthMethod {
+        match (self.token_endpoint_auth_method, &self.secret) {
+            (Some(method), _) => method,
+            (None, Some(_)) => ClientAuthMethod::ClientSecretBasic,
+            (None, None) => ClientAuthMethod::None,
+        }
+    }
+}
+
+/// How a client authenticates at the token endpoint, named as in the
+/// OAuth Token Endpoint Authentication Methods registry.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMethod {
+    /// The secret as HTTP Basic authentication
+    ClientSecretBasic,
+    /// The secret as `client_secret` parameter in the body
+    ClientSecretPost,
+    /// An assertion signed with the private key of the client (RFC 7523)
+    PrivateKeyJwt,
+    /// A public client that only sends its `client_id`
+    None,
+}
+
+impl ClientAuthMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientAuthMethod::ClientSecretBasic => "client_secret_basic",
+            ClientAuthMethod::ClientSecretPost => "client_secret_post",
+            ClientAuthMethod::PrivateKeyJwt => "private_key_jwt",
+            ClientAuthMethod::None => "none",
+        }
+    }
+}
+
+/// How the parameters of the authorization response are returned to the
+/// client, as defined by OAuth 2.0 Multiple Response Type Encoding Practices
+/// and Form Post Response Mode.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseMode {
+    /// In the query of the redirect URI
+    Query,
+    /// In the fragment of the redirect URI, which is not sent to servers
+    Fragment,
+    /// In a form that the browser automatically posts to the redirect URI
+    FormPost,
+}
+
+impl ResponseMode {
+    /// The mode for a `response_mode` parameter.
+    pub fn from_parameter(mode: &str) -> Option<ResponseMode> {
+        match mode {
+            "query" => Some(ResponseMode::Query),
+            "fragment" => Some(ResponseMode::Fragment),
+            "form_post" => Some(ResponseMode::FormPost),
+            _ => None,
+        }
+    }
+}
+
+/// Settings for the device authorization grant (RFC 8628).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeviceFlow {
+    pub enabled: bool,
+    /// The URI of the `/device` page as it is reachable by the user, i.e.
+    /// behind the protected path of the proxy.
+    pub verification_uri: String,
+    /// Lifetime of the device and user code in seconds
+    pub expires_in: i64,
+    /// Minimal number of seconds the client should wait between polling requests
+    pub interval: i64,
+}
+
+impl Default for DeviceFlow {
+    fn default() -> Self {
+        DeviceFlow {
+            enabled: false,
+            verification_uri: "http://localhost:8020/device".to_string(),
+            expires_in: 600,
+            interval: 5,
+        }
+    }
+}
+
+/// Settings for the authorization endpoint.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Authorization {
+    /// The issuer identifier of the server, i.e. its URL as reachable by
+    /// clients, which has to be the audience of request objects and is sent
+    /// as `iss` parameter in authorization responses (RFC 9207)
+    pub issuer: String,
+}
+
+impl Default for Authorization {
+    fn default() -> Self {
+        Authorization {
+            issuer: "http://localhost:8020".to_string(),
+        }
+    }
+}
+
+/// Settings for pushed authorization requests (RFC 9126).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PushedAuthorization {
+    pub enabled: bool,
+    /// Lifetime of a pushed request in seconds, which has to be long enough
+    /// for the user to log in at the proxy
+    pub expires_in: i64,
+}
+
+impl Default for PushedAuthorization {
+    fn default() -> Self {
+        PushedAuthorization {
+            enabled: false,
+            expires_in: 300,
+        }
+    }
+}
+
+/// Audiences a client is allowed to exchange tokens for.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TokenExchangePermission {
+    pub client: String,
+    pub audiences: Vec<String>,
+    /// Other clients whose tokens the client may exchange, otherwise only
+    /// tokens issued to the client or intended for it can be exchanged
+    #[serde(default)]
+    pub subject_clients: Vec<String>,
+}
+
+/// Settings for the OAuth 2.0 Token Exchange (RFC 8693).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TokenExchange {
+    pub enabled: bool,
+    /// Lifetime of the exchanged tokens in seconds
+    pub lifetime: i64,
+    pub permissions: Vec<TokenExchangePermission>,
+}
+
+impl Default for TokenExchange {
+    fn default() -> Self {
+        TokenExchange {
+            enabled: false,
+            lifetime: 300,
+            permissions: Vec::default(),
+        }
+    }
+}
+
+impl TokenExchange {
+    /// Checks if the client is allowed to exchange a token for the given audience.
+    pub fn is_allowed(&self, client_id: &str, audience: &str) -> bool {
+        self.permissions
+            .iter()
+            .any(|p| p.client == client_id && p.audiences.iter().any(|a| a == audience))
+    }
+
+    /// Checks if the client may exchange tokens that have been issued to another client.
+    pub fn allows_subject_client(&self, client_id: &str, subject_client: &str) -> bool {
+        self.permissions
+            .iter()
+            .any(|p| p.client == client_id && p.subject_clients.iter().any(|c| c == subject_client))
+    }
+}
+
+/// Custom HTML pages that are shown if the login fails.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Pages {
+    /// Directory with the handlebars templates `denied.html`,
+    /// `invalid_client.html` and `error.html`, and their language variants
+    /// like `denied.de.html`
+    pub directory: Option<String>,
+}
+
+/// Settings for the logout endpoint.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Logout {
+    /// Logout handler of the Shibboleth SP the user is redirected to, e.g.
+    /// `/Shibboleth.sso/Logout`, to also end the session at the proxy
+    pub shibboleth_logout_url: Option<String>,
+}
+
+/// Settings for the consent of users to share their attributes with a client.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Consent {
+    /// JSON file the consent of the users is saved to, only kept in memory if not set
+    pub store_file: Option<String>,
+}
+
+/// Settings for the dynamic client registration (RFC 7591 and RFC 7592).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Registration {
+    pub enabled: bool,
+    /// Bearer token that has to be sent to register a new client, clients can not be registered without it
+    pub initial_access_token: Option<String>,
+    /// The URI of the `/register` endpoint as it is reachable by clients,
+    /// which is used to create the URI each client can manage its registration at
+    pub endpoint: String,
+    /// Scopes registered clients may request
+    pub allowed_scopes: Vec<String>,
+    /// JSON file the registered clients are saved to, only kept in memory if not set
+    pub store_file: Option<String>,
+    /// Settings of the registered clients that are not part of their metadata
+    pub client: ClientTemplate,
+}
+
+impl Default for Registration {
+    fn default() -> Self {
+        Registration {
+            enabled: false,
+            initial_access_token: None,
+            endpoint: "http://localhost:8020/register".to_string(),
+            allowed_scopes: vec!["default-scope".to_string()],
+            store_file: None,
+            client: ClientTemplate::default(),
+        }
+    }
+}
+
+/// The settings dynamically registered clients get, in addition to their metadata.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ClientTemplate {
+    pub access_token_lifetime: i64,
+    pub refresh_token_lifetime: i64,
+    pub max_attribute_age: Option<i64>,
+    pub refresh_chain_lifetime: Option<i64>,
+    pub response_modes: Vec<ResponseMode>,
+    pub require_state: bool,
+    pub require_nonce: bool,
+    pub identity_source: IdentitySource,
+}
+
+impl Default for ClientTemplate {
+    fn default() -> Self {
+        let client = Client::default();
+        ClientTemplate {
+            access_token_lifetime: client.access_token_lifetime,
+            refresh_token_lifetime: client.refresh_token_lifetime,
+            max_attribute_age: client.max_attribute_age,
+            refresh_chain_lifetime: client.refresh_chain_lifetime,
+            response_modes: client.response_modes,
+            require_state: client.require_state,
+            require_nonce: client.require_nonce,
+            identity_source: client.identity_source,
+        }
+    }
+}
+
+/// Settings for the assertions of clients using `private_key_jwt` (RFC 7523).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ClientAssertion {
+    /// The URI of the `/token` endpoint as it is reachable by clients, which
+    /// has to be the audience of the assertions
+    pub audience: String,
+    /// Leeway in seconds when checking the expiry of an assertion
+    pub leeway: u64,
+}
+
+impl Default for ClientAssertion {
+    fn default() -> Self {
+        ClientAssertion {
+            audience: "http://localhost:8020/token".to_string(),
+            leeway: 60,
+        }
+    }
+}
+
+/// Settings for the audit log of authentication events.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Audit {
+    pub enabled: bool,
+    /// File the events are appended to, stdout if not set
+    pub file: Option<String>,
+    /// Attributes that are only logged as SHA-256 hash
+    pub hash_attributes: Vec<String>,
+    /// Attributes that are not logged at all
+    pub exclude_attributes: Vec<String>,
+    /// Proxies whose `X-Forwarded-For` header is used for the source IP
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// Settings for the Prometheus `/metrics` endpoint.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Metrics {
+    pub enabled: bool,
+    /// Serve the metrics on a separate port of the bind host instead of the main port
+    pub port: Option<u16>,
+}
+
+/// Settings for the administrative API at `/admin`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Admin {
+    pub enabled: bool,
+    /// Bearer token that has to be sent by the administrator, the API is not available without it
+    pub token: Option<String>,
+    /// Serve the API on a separate host, e.g. `127.0.0.1`, instead of the bind host
+    pub host: Option<String>,
+    /// Serve the API on a separate port instead of the main port
+    pub port: Option<u16>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Settings {
     pub logging: Logging,
     pub bind: Bind,
     pub client: Client,
     pub mapping: Mapping,
+    pub device_flow: DeviceFlow,
+    pub token_exchange: TokenExchange,
+    pub metrics: Metrics,
+    pub audit: Audit,
+    pub pages: Pages,
+    pub consent: Consent,
+    pub logout: Logout,
+    pub admin: Admin,
+    pub registration: Registration,
+    pub client_assertion: ClientAssertion,
+    pub pushed_authorization: PushedAuthorization,
+    pub authorization: Authorization,
+    /// The file the settings have been read from, which is read again when reloading
+    #[serde(skip)]
+    pub config_file: Option<String>,
 }
 
 impl Settings {
@@ -144,7 +928,50 @@ impl Settings {
 
         let from_file = config::File::new(&config_file, config::FileFormat::Toml);
         config.merge(from_file)?;
-        let result: Settings = config.try_into()?;
+        let mut result: Settings = config.try_into()?;
+        result.config_file = Some(config_file.to_string());
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_include_headers_from_file() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            r#"
+[mapping]
+include_headers = [
+    "X-Admin",
+    {{ name = "X-Remote-Groups", as = "groups" }},
+    {{ name = "Shib-*" }},
+]
+"#
+        )
+        .unwrap();
+
+        let settings = Settings::with_file(file.path().to_string_lossy().to_string()).unwrap();
+        assert_eq!(
+            vec![
+                IncludeHeader::Name("X-Admin".to_string()),
+                IncludeHeader::Renamed {
+                    name: "X-Remote-Groups".to_string(),
+                    alias: Some("groups".to_string()),
+                },
+                IncludeHeader::Renamed {
+                    name: "Shib-*".to_string(),
+                    alias: None,
+                },
+            ],
+            settings.mapping.include_headers
+        );
+        assert_eq!(
+            vec!["groups"],
+            settings.mapping.variable_names("X-Remote-Groups")
+        );
+    }
+}
@@ -1,17 +1,57 @@
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 
+use chrono::{DateTime, Duration, Utc};
+
+use crate::audit::AuditLog;
+use crate::auth_codes::AuthCodeStore;
+use crate::client_store::{ClientStore, RegisteredClient};
+use crate::consent::ConsentStore;
+use crate::device::DeviceCodeStore;
 use crate::errors::StartupError;
 use crate::jwt::JWTIssuer;
-use crate::settings::Settings;
+use crate::metrics::{Metrics, StoreSizes};
+use crate::pages::Pages;
+use crate::pushed_requests::PushedRequestStore;
+use crate::registrar::ClientRegistry;
+use crate::registration::load_client;
+use crate::settings::{self, JWTVerification, Settings};
 use oxide_auth::frontends::simple::endpoint::{Generic, Vacant};
 use oxide_auth::primitives::prelude::*;
-use oxide_auth::primitives::registrar::RegisteredUrl;
 
 pub struct State {
-    registrar: Mutex<ClientMap>,
-    authorizer: Mutex<AuthMap<RandomGenerator>>,
+    registrar: Mutex<ClientRegistry>,
+    authorizer: Mutex<AuthCodeStore>,
     issuer: Mutex<JWTIssuer>,
-    pub settings: Settings,
+    device_codes: Mutex<DeviceCodeStore>,
+    consents: Mutex<ConsentStore>,
+    client_store: Mutex<ClientStore>,
+    pushed_requests: Mutex<PushedRequestStore>,
+    pub metrics: Arc<Metrics>,
+    pub audit: Arc<AuditLog>,
+    pub pages: Pages,
+    current: RwLock<CurrentSettings>,
+}
+
+/// The settings and the keys they replaced, which are changed together.
+struct CurrentSettings {
+    settings: Arc<Settings>,
+    /// Keys used before key rotations, which are still accepted when verifying tokens
+    previous_keys: Vec<PreviousKey>,
+}
+
+/// A replaced signing key, which is kept until all tokens signed with it have expired.
+struct PreviousKey {
+    key: JWTVerification,
+    until: DateTime<Utc>,
+}
+
+/// The lifetime in seconds of the longest living tokens that can be issued
+/// with the settings for the registered clients.
+fn longest_token_lifetime(settings: &Settings, clients: &[&settings::Client]) -> i64 {
+    clients
+        .iter()
+        .map(|client| client.access_token_lifetime)
+        .fold(settings.token_exchange.lifetime, i64::max)
 }
 
 impl State {
@@ -26,39 +66,275 @@ impl State {
         }
     }
 
-    pub fn new(settings: &Settings) -> Result<Self, StartupError> {
-        let additional_redirect_uris: Vec<_> = settings
-            .client
-            .additional_redirect_uris
-            .iter()
-            .filter_map(|u| u.parse::<url::Url>().ok())
-            .map(|u| RegisteredUrl::Semantic(u))
-            .collect();
-        let client = if let Some(client_secret) = &settings.client.secret {
-            Client::confidential(
-                &settings.client.id,
-                settings.client.redirect_uri.parse::<url::Url>()?.into(),
-                "default-scope".parse()?,
-                client_secret.as_bytes(),
-            )
-            .with_additional_redirect_uris(additional_redirect_uris)
-        } else {
-            Client::public(
-                &settings.client.id,
-                settings.client.redirect_uri.parse::<url::Url>()?.into(),
-                "default-scope".parse()?,
-            )
-            .with_additional_redirect_uris(additional_redirect_uris)
+    /// The endpoint for issuing tokens, which are recorded in the audit log
+    /// with the source IP of the request.
+    pub fn token_endpoint(
+        &self,
+        source_ip: Option<String>,
+    ) -> Generic<impl Registrar + '_, impl Authorizer + '_, impl Issuer + '_> {
+        // Lock in the same order as the other endpoints
+        let registrar = self.registrar.lock().unwrap();
+        let authorizer = self.authorizer.lock().unwrap();
+        let mut issuer = self.issuer();
+        issuer.set_source_ip(source_ip);
+        Generic {
+            registrar,
+            authorizer,
+            issuer,
+            solicitor: Vacant,
+            scopes: Vacant,
+            response: Vacant,
+        }
+    }
+
+    pub fn registrar(&self) -> MutexGuard<'_, ClientRegistry> {
+        self.registrar.lock().unwrap()
+    }
+
+    /// Register a client or replace an existing client with the same ID, so
+    /// it can be authorized and gets tokens with its lifetimes.
+    pub fn register_client(&self, client: &settings::Client) -> Result<(), StartupError> {
+        let mut registrar = self.registrar();
+        registrar.register_client(client)?;
+        self.issuer().register_client(client);
+        Ok(())
+    }
+
+    /// Remove a client and revoke its refresh tokens.
+    ///
+    /// Returns the number of revoked tokens.
+    pub fn unregister_client(&self, client_id: &str) -> usize {
+        let mut registrar = self.registrar();
+        registrar.unregister_client(client_id);
+        self.issuer().unregister_client(client_id)
+    }
+
+    pub fn authorizer(&self) -> MutexGuard<'_, AuthCodeStore> {
+        self.authorizer.lock().unwrap()
+    }
+
+    pub fn issuer(&self) -> MutexGuard<'_, JWTIssuer> {
+        self.issuer.lock().unwrap()
+    }
+
+    pub fn device_codes(&self) -> MutexGuard<'_, DeviceCodeStore> {
+        self.device_codes.lock().unwrap()
+    }
+
+    pub fn consents(&self) -> MutexGuard<'_, ConsentStore> {
+        self.consents.lock().unwrap()
+    }
+
+    pub fn client_store(&self) -> MutexGuard<'_, ClientStore> {
+        self.client_store.lock().unwrap()
+    }
+
+    pub fn pushed_requests(&self) -> MutexGuard<'_, PushedRequestStore> {
+        self.pushed_requests.lock().unwrap()
+    }
+
+    /// The current settings, which might change when they are reloaded.
+    pub fn settings(&self) -> Arc<Settings> {
+        self.current.read().unwrap().settings.clone()
+    }
+
+    /// The keys tokens are verified with, the current key first.
+    pub fn verification_keys(&self) -> Vec<JWTVerification> {
+        let current = self.current.read().unwrap();
+        let now = Utc::now();
+        let mut keys = vec![current.settings.client.token_verification.clone()];
+        keys.extend(
+            current
+                .previous_keys
+                .iter()
+                .filter(|previous| previous.until > now)
+                .map(|previous| previous.key.clone()),
+        );
+        keys
+    }
+
+    /// Apply new settings for the client, the token mapping and the endpoints.
+    ///
+    /// If the signing key changed, the previous key is kept to verify tokens
+    /// that have been issued before. Settings that are only used when starting
+    /// the server, like the bind address, logging or the audit log, are not changed.
+    pub fn reload(&self, settings: Settings) -> Result<(), StartupError> {
+        self.update(|_| settings)
+    }
+
+    /// Replace the signing key by a new one and keep the previous one to verify tokens.
+    pub fn rotate_key(&self, key: JWTVerification) -> Result<(), StartupError> {
+        self.update(|current| {
+            let mut settings = current.clone();
+            settings.client.token_verification = key;
+            settings
+        })
+    }
+
+    /// Apply the settings created from the current ones to the registrar, the
+    /// issuer and the settings, which are all locked until they are updated,
+    /// and then to the stores of device codes and pushed requests.
+    ///
+    /// The registered clients are created again, so they get the current
+    /// settings of the `[registration.client]` template, and the configured
+    /// client is removed if its ID changed.
+    fn update(&self, new_settings: impl FnOnce(&Settings) -> Settings) -> Result<(), StartupError> {
+        let registered: Vec<RegisteredClient> = self.client_store().clients().cloned().collect();
+        let settings = {
+            // Lock in the same order as the endpoints, the settings last because
+            // they are read while the endpoints are locked
+            let mut registrar = self.registrar();
+            let mut issuer = self.issuer();
+            let mut current = self.current.write().unwrap();
+
+            let settings = new_settings(&current.settings);
+            let previous_lifetime = longest_token_lifetime(&current.settings, &registrar.clients());
+            let mut clients = vec![settings.client.clone()];
+            for registered in &registered {
+                clients.push(load_client(registered, &settings)?);
+            }
+            // A configured client that has been replaced by one with another ID is removed
+            let previous_id = &current.settings.client.id;
+            if *previous_id != settings.client.id {
+                registrar.unregister_client(previous_id);
+                issuer.unregister_client(previous_id);
+            }
+            for client in &clients {
+                registrar.register_client(client)?;
+                issuer.register_client(client);
+            }
+            registrar.set_client_assertion(&settings.client_assertion);
+            issuer.set_settings(settings.clone());
+
+            // Tokens signed with the previous key can be used until they expire
+            let now = Utc::now();
+            current
+                .previous_keys
+                .retain(|previous| previous.until > now);
+            if current.settings.client.token_verification != settings.client.token_verification {
+                let previous = PreviousKey {
+                    key: current.settings.client.token_verification.clone(),
+                    until: now + Duration::seconds(previous_lifetime),
+                };
+                current.previous_keys.push(previous);
+            }
+            current.settings = Arc::new(settings);
+            current.settings.clone()
         };
-        let registrar = vec![client].into_iter().collect();
-        let authorizer = AuthMap::new(RandomGenerator::new(16));
-        let issuer = JWTIssuer::new(settings.clone());
+
+        // The stores are not locked together with the endpoints, so they are updated afterwards
+        self.device_codes().set_settings(&settings.device_flow);
+        self.pushed_requests()
+            .set_settings(&settings.pushed_authorization);
+        Ok(())
+    }
+
+    /// The current sizes of the stores for the metrics.
+    pub fn store_sizes(&self) -> StoreSizes {
+        StoreSizes {
+            refresh_tokens: self.issuer().refresh_tokens(),
+            authorization_codes: self.authorizer().len(),
+            device_codes: self.device_codes().len(),
+            pushed_requests: self.pushed_requests().len(),
+        }
+    }
+
+    pub fn new(settings: &Settings) -> Result<Self, StartupError> {
+        let metrics = Arc::new(Metrics::default());
+        let mut registrar = ClientRegistry::new(metrics.clone());
+        registrar.set_client_assertion(&settings.client_assertion);
+        registrar.register_client(&settings.client)?;
+        let client_store = ClientStore::new(&settings.registration)?;
+        for registered in client_store.clients() {
+            registrar.register_client(&load_client(registered, settings)?)?;
+        }
+        let authorizer = AuthCodeStore::new();
+        let audit = Arc::new(AuditLog::new(&settings.audit)?);
+        let mut issuer = JWTIssuer::new(settings.clone(), metrics.clone(), audit.clone());
+        for client in registrar.clients() {
+            issuer.register_client(client);
+        }
+        let device_codes = DeviceCodeStore::new(&settings.device_flow);
         let state = State {
             registrar: Mutex::new(registrar),
             issuer: Mutex::new(issuer),
             authorizer: Mutex::new(authorizer),
-            settings: settings.clone(),
+            device_codes: Mutex::new(device_codes),
+            consents: Mutex::new(ConsentStore::new(&settings.consent)?),
+            client_store: Mutex::new(client_store),
+            pushed_requests: Mutex::new(PushedRequestStore::new(&settings.pushed_authorization)),
+            metrics,
+            audit,
+            pages: Pages::new(&settings.pages),
+            current: RwLock::new(CurrentSettings {
+                settings: Arc::new(settings.clone()),
+                previous_keys: Vec::new(),
+            }),
         };
         Ok(state)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(secret: &str) -> JWTVerification {
+        JWTVerification::HS256 {
+            secret: secret.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_reload() {
+        let state = State::new(&Settings::default()).unwrap();
+        let mut settings = Settings::clone(&state.settings());
+        settings.client.id = "renamed".to_string();
+        settings.device_flow.expires_in = 60;
+        state.reload(settings).unwrap();
+
+        assert!(state.registrar().client("default").is_none());
+        assert!(state.registrar().client("renamed").is_some());
+        let mut device_codes = state.device_codes();
+        let (_, authorization) = device_codes.start(PreGrant {
+            client_id: "renamed".to_string(),
+            redirect_uri: "http://localhost:8080".parse::<url::Url>().unwrap().into(),
+            scope: "default-scope".parse().unwrap(),
+        });
+        assert!(
+            authorization.until.timestamp()
+                <= time::OffsetDateTime::now_utc().unix_timestamp() + 60
+        );
+    }
+
+    #[test]
+    fn test_keep_previous_keys() {
+        let settings = Settings::default();
+        let state = State::new(&settings).unwrap();
+        state.rotate_key(key("second")).unwrap();
+        state.rotate_key(key("third")).unwrap();
+        assert_eq!(
+            vec![
+                key("third"),
+                settings.client.token_verification.clone(),
+                key("second")
+            ],
+            state.verification_keys()
+        );
+
+        // Without token lifetimes, no token signed with the third key can still be valid
+        let mut settings = Settings::clone(&state.settings());
+        settings.client.access_token_lifetime = 0;
+        settings.token_exchange.lifetime = 0;
+        state.reload(settings).unwrap();
+        state.rotate_key(key("fourth")).unwrap();
+        assert_eq!(
+            vec![
+                key("fourth"),
+                Settings::default().client.token_verification,
+                key("second")
+            ],
+            state.verification_keys()
+        );
+    }
+}